@@ -12,8 +12,17 @@ import "@openzeppelin/contracts/token/ERC20/IERC20.sol";
 // with nft_shared_ptr
 contract NftPtrOwner is ERC721Holder, IERC20 {
     string private _name;
+    address private immutable _deployer;
     constructor(string memory name_) {
         _name = name_;
+        _deployer = msg.sender;
+    }
+
+    // Called when the nft_ptr is destroyed, if the library is set to destroy owner contracts.
+    // Tokens it holds stay assigned to its address.
+    function destroy() public {
+        require(msg.sender == _deployer, "NftPtrOwner: only the deployer can destroy");
+        selfdestruct(payable(msg.sender));
     }
     // IERC20: Stub ERC20 since a nft_ptr doesn't perform reference counting.
 
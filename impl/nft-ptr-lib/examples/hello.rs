@@ -4,8 +4,8 @@ use web3::types::{Address, U256};
 async fn main() {
     env_logger::init();
     info!("Hello!");
-    let mut lib = nft_ptr_lib::make_nft_ptr_lib_localhost();
-    lib.initialize().await;
+    let mut lib = nft_ptr_lib::make_nft_ptr_lib_localhost().unwrap();
+    lib.initialize().await.unwrap();
     //lib.mint_token(lib.account, U256::from(0x41414141), "Example")
     //    .await;
 }
@@ -0,0 +1,47 @@
+use crate::NftPtrError;
+use std::path::Path;
+use web3::ethabi;
+
+// A compiled contract, as written by contracts/dumpbytecode (hex bytecode plus its JSON ABI).
+// Both are parsed once, when it's loaded, rather than on every deploy.
+pub(crate) struct Artifact {
+    pub(crate) code: Vec<u8>,
+    pub(crate) abi: ethabi::Contract,
+}
+
+impl Artifact {
+    pub(crate) fn embedded_token() -> Artifact {
+        Artifact::new(
+            include_str!("../../../contracts/out/NftPtrToken.code"),
+            include_bytes!("../../../contracts/out/NftPtrToken.json"),
+        )
+        .expect("embedded NftPtrToken artifact")
+    }
+
+    pub(crate) fn embedded_owner() -> Artifact {
+        Artifact::new(
+            include_str!("../../../contracts/out/NftPtrOwner.code"),
+            include_bytes!("../../../contracts/out/NftPtrOwner.json"),
+        )
+        .expect("embedded NftPtrOwner artifact")
+    }
+
+    // Checks the artifact up front, so a bad one fails here instead of at deploy time.
+    pub(crate) fn new(bytecode: &str, abi: &[u8]) -> Result<Artifact, NftPtrError> {
+        let abi = ethabi::Contract::load(abi)
+            .map_err(|err| NftPtrError::Artifact(format!("invalid ABI: {}", err)))?;
+        let code = hex::decode(bytecode.trim())
+            .map_err(|err| NftPtrError::Artifact(format!("invalid bytecode: {}", err)))?;
+        Ok(Artifact { code, abi })
+    }
+
+    pub(crate) fn load(bytecode_path: &Path, abi_path: &Path) -> Result<Artifact, NftPtrError> {
+        let bytecode = std::fs::read_to_string(bytecode_path).map_err(|err| {
+            NftPtrError::Artifact(format!("can't read {}: {}", bytecode_path.display(), err))
+        })?;
+        let abi = std::fs::read(abi_path).map_err(|err| {
+            NftPtrError::Artifact(format!("can't read {}: {}", abi_path.display(), err))
+        })?;
+        Artifact::new(&bytecode, &abi)
+    }
+}
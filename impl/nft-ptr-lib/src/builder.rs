@@ -0,0 +1,731 @@
+use crate::artifact::Artifact;
+use crate::symbolizer::{ObjectContext, Symbolizer};
+use crate::{
+    system_clock_millis, NftPtrError, NftPtrLib, Stats, DEFAULT_HISTORY_LIMIT,
+    DEFAULT_INIT_CONCURRENCY, DEFAULT_RPC_TIMEOUT, NO_CONFIRMATIONS_OVERRIDE, NUM_CONFIRMATIONS,
+    TOKEN_BASE_URI,
+};
+use secp256k1::SecretKey;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::warn;
+use web3::futures::future::BoxFuture;
+use web3::types::{Address, U256};
+
+// How chatty the logs are. Tracking a busy program sends a transaction per pointer
+// assignment, so by default only the summaries are logged at info level.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verbosity {
+    // Deploy/initialize/shutdown summaries at info; per-move and per-nft_ptr messages at debug.
+    Summary,
+    // Per-move and per-nft_ptr messages at info as well.
+    Verbose,
+    // Like Summary, plus every TxEvent as a line of JSON at info under the
+    // "nft_ptr::event" target, for log formatters to pick up.
+    Structured,
+}
+
+// How C++ names (object types, and functions in caller PCs) are demangled for token URIs
+// and logs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DemangleMode {
+    // Everything the demangler gives us, e.g. "foo::bar(int, char const*)".
+    Full,
+    // Without function parameters and return types, e.g. "foo::bar", since heavily
+    // templated signatures can run to thousands of characters.
+    Compact,
+}
+
+// Who owns a token moved to or from an address that isn't a tracked nft_ptr (e.g. one whose
+// ptr_initialize was missed).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OwnerFallback {
+    // Our account, like address 0, so the token still has somewhere to go.
+    FallbackToAccount,
+    // Fail the move with NftPtrError::UntrackedPointer.
+    ErrorOnUnknown,
+    // Address::zero(), so these tokens can be told apart from ones we own.
+    FallbackToZero,
+}
+
+// Where the gas price for our transactions comes from.
+#[derive(Clone)]
+pub enum GasStrategy {
+    // Leave it to the node (or to eth_gasPrice, when signing locally).
+    NodeSuggested,
+    // Always this many wei per gas.
+    Fixed(U256),
+    // Asked before every transaction, e.g. for a percentile-based estimate from a gas station.
+    // The answer is awaited like an RPC, so it's bounded by the RPC timeout.
+    Oracle(Arc<dyn Fn() -> BoxFuture<'static, U256> + Send + Sync>),
+}
+
+// When a transaction counts as done.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfirmationPolicy {
+    // As soon as it's mined.
+    None,
+    // Once this many blocks have been mined on top of it.
+    Blocks(usize),
+    // Once the chain head's timestamp is this far past the block it was mined in,
+    // for chains with irregular block times where a block count means little.
+    UntilTimestamp(Duration),
+}
+
+#[derive(Clone)]
+pub(crate) struct Config {
+    // For contract deploys, which everything after them depends on.
+    pub(crate) deploy_confirmation_policy: ConfirmationPolicy,
+    // For calls on the token contract, e.g. mintOrMove, which can afford to be quicker.
+    pub(crate) call_confirmation_policy: ConfirmationPolicy,
+    pub(crate) use_hardcoded_gas: bool,
+    // Scales the hardcoded gas limits, e.g. 1.5 on a chain where mintOrMove costs more.
+    pub(crate) gas_buffer_multiplier: f64,
+    // Don't wait for moves to be mined; flush (or shutdown) checks them later.
+    pub(crate) fire_and_forget: bool,
+    // Log the transactions we'd send instead of sending them.
+    pub(crate) dry_run: bool,
+    // Sign with an explicit chain id, and spell out every option of transactions the node
+    // signs instead of leaving them to its defaults.
+    pub(crate) replay_protected: bool,
+    // After the node refuses a send over its nonce, resync from the node's count and send
+    // again. Off by default: if the refused send was a re-send of one the node already
+    // accepted (e.g. ReconnectingIpc retrying after a disconnect), this sends it twice.
+    pub(crate) resync_nonce_on_error: bool,
+    // Used in place of argv[0] in the token contract's name.
+    pub(crate) program_name: Option<String>,
+    // Used as the token contract's whole name instead of the program name and timestamp.
+    pub(crate) token_name: Option<String>,
+    // Without a token_name, name the token contract just "NftPtrToken", so deploys are
+    // reproducible.
+    pub(crate) deterministic_name: bool,
+    // If set, added to every token URI and owner contract name, so runs sharing a token
+    // contract (via save_state/load_state) can be told apart.
+    pub(crate) run_id: Option<String>,
+    // Timestamp (in milliseconds) baked into the token contract's name.
+    pub(crate) clock: Arc<dyn Fn() -> u128 + Send + Sync>,
+    // Skip mintOrMove for a self-assignment when the token is already owned by that
+    // contract. Off by default: it costs an ownerOf call on every such move, and it drops
+    // no-op moves from the on-chain record.
+    pub(crate) skip_redundant_moves: bool,
+    // Skip mintOrMove when the last move we sent for the token went to the same owner
+    // contract. Unlike skip_redundant_moves this needs no RPC, but it's off by default
+    // since it also skips deliberate re-mints.
+    pub(crate) dedup_moves: bool,
+    // Attach an EIP-2930 access list from eth_createAccessList to contract calls, which
+    // makes mintOrMove's storage reads cheaper. Off by default since not every node has the
+    // RPC, and only for transactions the node signs.
+    pub(crate) access_lists: bool,
+    // Selfdestruct an nft_ptr's owner contract when it's destroyed, instead of leaving it
+    // on-chain for inspection. Costs a transaction per destroy.
+    pub(crate) destroy_owner_contracts: bool,
+    pub(crate) verbosity: Verbosity,
+    pub(crate) demangle_mode: DemangleMode,
+    pub(crate) symbolizer: Symbolizer,
+    pub(crate) owner_fallback: OwnerFallback,
+    // If set, refuse to run on any network not in here, instead of only refusing mainnet.
+    pub(crate) allowed_network_ids: Option<HashSet<u32>>,
+    // Run on network id 1 anyway if the node is a local dev node (e.g. `anvil --fork-url`).
+    pub(crate) force_allow_fork: bool,
+    // If set, the one network id we expect the node to be on.
+    pub(crate) expected_chain_id: Option<u32>,
+    // Which of the node's unlocked accounts to send from when there's no local signing key.
+    pub(crate) account_index: usize,
+    // How long to wait for any one RPC request before giving up with NftPtrError::Timeout.
+    pub(crate) rpc_timeout: Duration,
+    // How long to wait for a transaction to be mined and confirmed, or None to wait forever.
+    pub(crate) request_timeout: Option<Duration>,
+    // How many owner contracts ptr_initialize_many deploys at once.
+    pub(crate) init_concurrency: usize,
+    pub(crate) gas_strategy: GasStrategy,
+    // Applied to whatever gas_strategy comes up with, e.g. 1.5 to outbid a congested
+    // testnet's mempool. With NodeSuggested, anything but 1.0 means asking eth_gasPrice.
+    pub(crate) gas_price_multiplier: f64,
+    // The token contract's baseTokenURI; may contain {contract} and {chain} placeholders.
+    pub(crate) token_base_uri: String,
+    // Token URIs longer than this (e.g. for deeply templated types) are replaced with a hash
+    // of the URI, to bound mintOrMove's calldata.
+    pub(crate) max_token_uri_len: Option<usize>,
+    // Append every transaction, and every nft_ptr destroyed, to this file as JSON lines.
+    pub(crate) audit_log_path: Option<PathBuf>,
+    // How many transactions NftPtrLib::history keeps in memory, dropping the oldest first.
+    pub(crate) history_limit: usize,
+    // How many token contracts to deploy and spread tokens across. Every move is still sent
+    // from one account, one nonce at a time, so this doesn't make moves any faster.
+    pub(crate) token_shards: usize,
+    // (value, object type) of tokens to mint right after deploying the token contract.
+    pub(crate) preset_tokens: Vec<(u64, String)>,
+}
+
+// Configures an NftPtrLib. Defaults come from the NFT_PTR_* environment variables,
+// since that's the only way to configure the library from the C++ side.
+pub struct NftPtrLibBuilder {
+    config: Config,
+    private_key: Option<String>,
+    // (mnemonic, derivation path)
+    mnemonic: Option<(String, String)>,
+    // (bytecode, ABI) paths to load instead of the compiled-in contracts.
+    token_artifact_paths: Option<(PathBuf, PathBuf)>,
+    owner_artifact_paths: Option<(PathBuf, PathBuf)>,
+    // A malformed NFT_PTR_ALLOWED_NETWORK_IDS, which build returns rather than ignoring.
+    env_error: Option<NftPtrError>,
+}
+
+impl NftPtrLibBuilder {
+    pub fn new() -> NftPtrLibBuilder {
+        let num_confirmations =
+            env_number("NFT_PTR_NUM_CONFIRMATIONS").unwrap_or(NUM_CONFIRMATIONS);
+        let deploy_confirmations =
+            env_number("NFT_PTR_DEPLOY_CONFIRMATIONS").unwrap_or(num_confirmations);
+        let call_confirmations =
+            env_number("NFT_PTR_CALL_CONFIRMATIONS").unwrap_or(num_confirmations);
+        let (allowed_network_ids, env_error) =
+            match std::env::var("NFT_PTR_ALLOWED_NETWORK_IDS").map(|ids| parse_network_ids(&ids)) {
+                Ok(Ok(ids)) => (Some(ids), None),
+                Ok(Err(err)) => (None, Some(err)),
+                Err(_) => (None, None),
+            };
+        let builder = NftPtrLibBuilder {
+            config: Config {
+                deploy_confirmation_policy: ConfirmationPolicy::Blocks(deploy_confirmations),
+                call_confirmation_policy: ConfirmationPolicy::Blocks(call_confirmations),
+                use_hardcoded_gas: std::env::var("NFT_PTR_NO_HARDCODED_GAS").is_err(),
+                gas_buffer_multiplier: env_number("NFT_PTR_GAS_BUFFER_MULTIPLIER").unwrap_or(1.0),
+                dry_run: std::env::var("NFT_PTR_DRY_RUN").is_ok(),
+                fire_and_forget: std::env::var("NFT_PTR_FIRE_AND_FORGET").is_ok(),
+                replay_protected: std::env::var("NFT_PTR_REPLAY_PROTECTED").is_ok(),
+                resync_nonce_on_error: std::env::var("NFT_PTR_RESYNC_NONCE").is_ok(),
+                program_name: None,
+                token_name: std::env::var("NFT_PTR_TOKEN_NAME").ok(),
+                deterministic_name: std::env::var("NFT_PTR_DETERMINISTIC_NAME").is_ok(),
+                run_id: std::env::var("NFT_PTR_RUN_ID").ok(),
+                clock: Arc::new(system_clock_millis),
+                skip_redundant_moves: std::env::var("NFT_PTR_SKIP_REDUNDANT_MOVES").is_ok(),
+                dedup_moves: std::env::var("NFT_PTR_DEDUP_MOVES").is_ok(),
+                access_lists: std::env::var("NFT_PTR_ACCESS_LISTS").is_ok(),
+                destroy_owner_contracts: std::env::var("NFT_PTR_DESTROY_OWNER_CONTRACTS").is_ok(),
+                verbosity: match std::env::var("NFT_PTR_VERBOSITY").as_deref() {
+                    Ok("verbose") => Verbosity::Verbose,
+                    Ok("structured") => Verbosity::Structured,
+                    _ => Verbosity::Summary,
+                },
+                demangle_mode: match std::env::var("NFT_PTR_DEMANGLE").as_deref() {
+                    Ok("compact") => DemangleMode::Compact,
+                    _ => DemangleMode::Full,
+                },
+                symbolizer: env_symbolizer(),
+                owner_fallback: match std::env::var("NFT_PTR_UNKNOWN_OWNER").as_deref() {
+                    Ok("error") => OwnerFallback::ErrorOnUnknown,
+                    Ok("zero") => OwnerFallback::FallbackToZero,
+                    _ => OwnerFallback::FallbackToAccount,
+                },
+                allowed_network_ids,
+                force_allow_fork: std::env::var("NFT_PTR_FORCE_ALLOW_FORK").is_ok(),
+                rpc_timeout: env_number("NFT_PTR_RPC_TIMEOUT_SECS")
+                    .map(Duration::from_secs)
+                    .unwrap_or(DEFAULT_RPC_TIMEOUT),
+                request_timeout: env_number("NFT_PTR_REQUEST_TIMEOUT_SECS")
+                    .map(Duration::from_secs),
+                init_concurrency: env_number("NFT_PTR_INIT_CONCURRENCY")
+                    .unwrap_or(DEFAULT_INIT_CONCURRENCY),
+                // Decimal wei; U256's FromStr would read it as hex.
+                gas_strategy: match env_parsed("NFT_PTR_GAS_PRICE", |gas_price| {
+                    U256::from_dec_str(gas_price).ok()
+                }) {
+                    Some(gas_price) => GasStrategy::Fixed(gas_price),
+                    None => GasStrategy::NodeSuggested,
+                },
+                token_base_uri: std::env::var("NFT_PTR_TOKEN_BASE_URI")
+                    .unwrap_or_else(|_| TOKEN_BASE_URI.to_string()),
+                max_token_uri_len: env_number("NFT_PTR_MAX_TOKEN_URI_LEN"),
+                audit_log_path: std::env::var_os("NFT_PTR_AUDIT_LOG").map(PathBuf::from),
+                history_limit: env_number("NFT_PTR_HISTORY_LIMIT").unwrap_or(DEFAULT_HISTORY_LIMIT),
+                token_shards: env_number("NFT_PTR_TOKEN_SHARDS").unwrap_or(1),
+                preset_tokens: Vec::new(),
+                gas_price_multiplier: env_number("NFT_PTR_GAS_PRICE_MULTIPLIER").unwrap_or(1.0),
+                expected_chain_id: env_number("NFT_PTR_EXPECTED_CHAIN_ID"),
+                account_index: env_number("NFT_PTR_ACCOUNT_INDEX").unwrap_or(0),
+            },
+            private_key: std::env::var("NFT_PTR_PRIVATE_KEY").ok(),
+            mnemonic: std::env::var("NFT_PTR_MNEMONIC").ok().map(|mnemonic| {
+                (
+                    mnemonic,
+                    std::env::var("NFT_PTR_DERIVATION_PATH")
+                        .unwrap_or_else(|_| DEFAULT_DERIVATION_PATH.to_string()),
+                )
+            }),
+            token_artifact_paths: None,
+            owner_artifact_paths: None,
+            env_error,
+        };
+        match std::env::var_os("NFT_PTR_CONTRACTS_DIR") {
+            Some(dir) => builder.contract_artifacts_dir(dir),
+            None => builder,
+        }
+    }
+
+    // Sets both deploy_confirmations and call_confirmations.
+    pub fn num_confirmations(mut self, num_confirmations: usize) -> NftPtrLibBuilder {
+        self.config.deploy_confirmation_policy = ConfirmationPolicy::Blocks(num_confirmations);
+        self.config.call_confirmation_policy = ConfirmationPolicy::Blocks(num_confirmations);
+        self
+    }
+
+    pub fn deploy_confirmations(mut self, deploy_confirmations: usize) -> NftPtrLibBuilder {
+        self.config.deploy_confirmation_policy = ConfirmationPolicy::Blocks(deploy_confirmations);
+        self
+    }
+
+    pub fn call_confirmations(mut self, call_confirmations: usize) -> NftPtrLibBuilder {
+        self.config.call_confirmation_policy = ConfirmationPolicy::Blocks(call_confirmations);
+        self
+    }
+
+    // For both deploys and calls.
+    pub fn confirmation_policy(
+        mut self,
+        confirmation_policy: ConfirmationPolicy,
+    ) -> NftPtrLibBuilder {
+        self.config.deploy_confirmation_policy = confirmation_policy;
+        self.config.call_confirmation_policy = confirmation_policy;
+        self
+    }
+
+    pub fn use_hardcoded_gas(mut self, use_hardcoded_gas: bool) -> NftPtrLibBuilder {
+        self.config.use_hardcoded_gas = use_hardcoded_gas;
+        self
+    }
+
+    pub fn gas_buffer_multiplier(mut self, gas_buffer_multiplier: f64) -> NftPtrLibBuilder {
+        self.config.gas_buffer_multiplier = gas_buffer_multiplier;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> NftPtrLibBuilder {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    // move_token returns as soon as the node has accepted the transaction. Reverted moves
+    // only show up, and only reach the history and tx listener, when flush is called.
+    pub fn fire_and_forget(mut self, fire_and_forget: bool) -> NftPtrLibBuilder {
+        self.config.fire_and_forget = fire_and_forget;
+        self
+    }
+
+    // Fetches the chain id once at initialize and signs every transaction with it (EIP-155).
+    // Transactions the node signs get their gas price and limit filled in up front; the node
+    // still picks the chain id for those, so prefer a local key where it matters.
+    pub fn replay_protected(mut self, replay_protected: bool) -> NftPtrLibBuilder {
+        self.config.replay_protected = replay_protected;
+        self
+    }
+
+    pub fn resync_nonce_on_error(mut self, resync_nonce_on_error: bool) -> NftPtrLibBuilder {
+        self.config.resync_nonce_on_error = resync_nonce_on_error;
+        self
+    }
+
+    pub fn program_name(mut self, program_name: &str) -> NftPtrLibBuilder {
+        self.config.program_name = Some(program_name.to_string());
+        self
+    }
+
+    pub fn token_name(mut self, token_name: &str) -> NftPtrLibBuilder {
+        self.config.token_name = Some(token_name.to_string());
+        self
+    }
+
+    pub fn deterministic_name(mut self, deterministic_name: bool) -> NftPtrLibBuilder {
+        self.config.deterministic_name = deterministic_name;
+        self
+    }
+
+    pub fn run_id(mut self, run_id: &str) -> NftPtrLibBuilder {
+        self.config.run_id = Some(run_id.to_string());
+        self
+    }
+
+    // Overrides the wall clock used to name the token contract, so tests can pin the name.
+    pub fn clock(mut self, clock: impl Fn() -> u128 + Send + Sync + 'static) -> NftPtrLibBuilder {
+        self.config.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn skip_redundant_moves(mut self, skip_redundant_moves: bool) -> NftPtrLibBuilder {
+        self.config.skip_redundant_moves = skip_redundant_moves;
+        self
+    }
+
+    pub fn dedup_moves(mut self, dedup_moves: bool) -> NftPtrLibBuilder {
+        self.config.dedup_moves = dedup_moves;
+        self
+    }
+
+    pub fn access_lists(mut self, access_lists: bool) -> NftPtrLibBuilder {
+        self.config.access_lists = access_lists;
+        self
+    }
+
+    pub fn destroy_owner_contracts(mut self, destroy_owner_contracts: bool) -> NftPtrLibBuilder {
+        self.config.destroy_owner_contracts = destroy_owner_contracts;
+        self
+    }
+
+    pub fn verbosity(mut self, verbosity: Verbosity) -> NftPtrLibBuilder {
+        self.config.verbosity = verbosity;
+        self
+    }
+
+    pub fn demangle_mode(mut self, demangle_mode: DemangleMode) -> NftPtrLibBuilder {
+        self.config.demangle_mode = demangle_mode;
+        self
+    }
+
+    pub fn owner_fallback(mut self, owner_fallback: OwnerFallback) -> NftPtrLibBuilder {
+        self.config.owner_fallback = owner_fallback;
+        self
+    }
+
+    pub fn symbolizer(mut self, symbolizer: Symbolizer) -> NftPtrLibBuilder {
+        self.config.symbolizer = symbolizer;
+        self
+    }
+
+    // Only run on these network ids (as reported by net_version), e.g. a set of private test
+    // chains, so a misconfigured RPC URL can't point the library at the wrong chain.
+    pub fn allowed_network_ids(
+        mut self,
+        allowed_network_ids: impl IntoIterator<Item = u32>,
+    ) -> NftPtrLibBuilder {
+        self.config.allowed_network_ids = Some(allowed_network_ids.into_iter().collect());
+        // Replaces whatever NFT_PTR_ALLOWED_NETWORK_IDS said, so its typo no longer matters.
+        self.env_error = None;
+        self
+    }
+
+    // Lets the library run against a local fork of mainnet, which reports network id 1, for
+    // realistic gas costs. Mainnet is still refused unless web3_clientVersion says the node
+    // is Anvil, Hardhat or Ganache.
+    pub fn force_allow_fork(mut self, force_allow_fork: bool) -> NftPtrLibBuilder {
+        self.config.force_allow_fork = force_allow_fork;
+        self
+    }
+
+    pub fn rpc_timeout(mut self, rpc_timeout: Duration) -> NftPtrLibBuilder {
+        self.config.rpc_timeout = rpc_timeout;
+        self
+    }
+
+    // Fails an operation with NftPtrError::Timeout if its transaction isn't confirmed within
+    // `request_timeout`, e.g. because it was underpriced and is stuck in the mempool.
+    pub fn request_timeout(mut self, request_timeout: Duration) -> NftPtrLibBuilder {
+        self.config.request_timeout = Some(request_timeout);
+        self
+    }
+
+    pub fn init_concurrency(mut self, init_concurrency: usize) -> NftPtrLibBuilder {
+        self.config.init_concurrency = init_concurrency;
+        self
+    }
+
+    pub fn gas_strategy(mut self, gas_strategy: GasStrategy) -> NftPtrLibBuilder {
+        self.config.gas_strategy = gas_strategy;
+        self
+    }
+
+    pub fn gas_price_multiplier(mut self, gas_price_multiplier: f64) -> NftPtrLibBuilder {
+        self.config.gas_price_multiplier = gas_price_multiplier;
+        self
+    }
+
+    // e.g. "https://example.com/{chain}/{contract}/?"
+    pub fn token_base_uri(mut self, token_base_uri: &str) -> NftPtrLibBuilder {
+        self.config.token_base_uri = token_base_uri.to_string();
+        self
+    }
+
+    // The full URI of a hashed one is logged, and written to the audit log, for the
+    // metadata server to look up by hash.
+    pub fn max_token_uri_len(mut self, max_token_uri_len: usize) -> NftPtrLibBuilder {
+        self.config.max_token_uri_len = Some(max_token_uri_len);
+        self
+    }
+
+    // Fails initialize with NftPtrError::UnexpectedChain if the node reports any other
+    // network id, e.g. because the RPC URL is for Goerli when you meant Sepolia.
+    pub fn expected_chain_id(mut self, expected_chain_id: u32) -> NftPtrLibBuilder {
+        self.config.expected_chain_id = Some(expected_chain_id);
+        self
+    }
+
+    pub fn account_index(mut self, account_index: usize) -> NftPtrLibBuilder {
+        self.config.account_index = account_index;
+        self
+    }
+
+    // Signs with this hex private key instead of a node account, like NFT_PTR_PRIVATE_KEY.
+    // It's an error to set this as well as NFT_PTR_KEYSTORE.
+    pub fn private_key(mut self, private_key: &str) -> NftPtrLibBuilder {
+        self.private_key = Some(private_key.to_string());
+        self
+    }
+
+    // Signs with the key derived from a BIP-39 mnemonic (like the one Ganache or Anvil print
+    // at startup) along a BIP-32 path such as "m/44'/60'/0'/0/0", like NFT_PTR_MNEMONIC and
+    // NFT_PTR_DERIVATION_PATH.
+    pub fn mnemonic(mut self, mnemonic: &str, derivation_path: &str) -> NftPtrLibBuilder {
+        self.mnemonic = Some((mnemonic.to_string(), derivation_path.to_string()));
+        self
+    }
+
+    // Deploys this many token contracts; the token for `value` goes on number value % shards.
+    pub fn token_shards(mut self, token_shards: usize) -> NftPtrLibBuilder {
+        self.config.token_shards = token_shards;
+        self
+    }
+
+    // Mints these tokens to our account as soon as the token contract is deployed, so the
+    // collection has something in it for a demo before any instrumented program runs.
+    pub fn preset_tokens(mut self, preset_tokens: Vec<(u64, String)>) -> NftPtrLibBuilder {
+        self.config.preset_tokens = preset_tokens;
+        self
+    }
+
+    pub fn audit_log_path(mut self, audit_log_path: impl Into<PathBuf>) -> NftPtrLibBuilder {
+        self.config.audit_log_path = Some(audit_log_path.into());
+        self
+    }
+
+    // 0 keeps no history at all.
+    pub fn history_limit(mut self, history_limit: usize) -> NftPtrLibBuilder {
+        self.config.history_limit = history_limit;
+        self
+    }
+
+    // Loads NftPtrToken's bytecode and ABI from these files (as written by
+    // contracts/dumpbytecode) when the lib is built, instead of using the ones compiled in.
+    pub fn token_artifacts(
+        mut self,
+        bytecode_path: impl Into<PathBuf>,
+        abi_path: impl Into<PathBuf>,
+    ) -> NftPtrLibBuilder {
+        self.token_artifact_paths = Some((bytecode_path.into(), abi_path.into()));
+        self
+    }
+
+    // Same as token_artifacts, for NftPtrOwner.
+    pub fn owner_artifacts(
+        mut self,
+        bytecode_path: impl Into<PathBuf>,
+        abi_path: impl Into<PathBuf>,
+    ) -> NftPtrLibBuilder {
+        self.owner_artifact_paths = Some((bytecode_path.into(), abi_path.into()));
+        self
+    }
+
+    // Loads both contracts from a directory laid out like contracts/out (NftPtrToken.code,
+    // NftPtrToken.json, NftPtrOwner.code, NftPtrOwner.json), e.g. after editing the Solidity,
+    // without rebuilding the library.
+    pub fn contract_artifacts_dir(self, dir: impl Into<PathBuf>) -> NftPtrLibBuilder {
+        let dir = dir.into();
+        self.token_artifacts(dir.join("NftPtrToken.code"), dir.join("NftPtrToken.json"))
+            .owner_artifacts(dir.join("NftPtrOwner.code"), dir.join("NftPtrOwner.json"))
+    }
+
+    pub fn build<T: web3::Transport>(self, transport: T) -> Result<NftPtrLib<T>, NftPtrError> {
+        self.build_with_web3(web3::Web3::new(transport))
+    }
+
+    // Like build, but reuses an existing Web3 client instead of wrapping a transport.
+    pub fn build_with_web3<T: web3::Transport>(
+        mut self,
+        web3: web3::Web3<T>,
+    ) -> Result<NftPtrLib<T>, NftPtrError> {
+        if let Some(err) = self.env_error.take() {
+            return Err(err);
+        }
+        let keystore_path = std::env::var("NFT_PTR_KEYSTORE");
+        let num_key_sources = [
+            self.private_key.is_some(),
+            self.mnemonic.is_some(),
+            keystore_path.is_ok(),
+        ]
+        .iter()
+        .filter(|set| **set)
+        .count();
+        if num_key_sources > 1 {
+            return Err(NftPtrError::Keystore(
+                "only one of NFT_PTR_KEYSTORE, a private key and a mnemonic can be set".to_string(),
+            ));
+        }
+        let account_private_key = if let Some(private_key) = &self.private_key {
+            Some(parse_private_key(private_key)?)
+        } else if let Some((mnemonic, derivation_path)) = &self.mnemonic {
+            Some(derive_key(mnemonic, derivation_path)?)
+        } else if let Ok(keystore_path) = keystore_path {
+            let keystore_str = std::fs::read_to_string(&keystore_path)
+                .map_err(|err| NftPtrError::KeystoreRead(keystore_path, err))?;
+            let password =
+                std::env::var("NFT_PTR_PASSWORD").map_err(|_| NftPtrError::PasswordMissing)?;
+            let keystore = keystore_loader::load_keystore_from_string(&keystore_str, &password)
+                .map_err(|err| NftPtrError::KeystoreDecrypt(err.to_string()))?;
+            Some(keystore)
+        } else {
+            None
+        };
+        let token_artifact = match &self.token_artifact_paths {
+            Some((bytecode_path, abi_path)) => Artifact::load(bytecode_path, abi_path)?,
+            None => Artifact::embedded_token(),
+        };
+        let owner_artifact = match &self.owner_artifact_paths {
+            Some((bytecode_path, abi_path)) => Artifact::load(bytecode_path, abi_path)?,
+            None => Artifact::embedded_owner(),
+        };
+        // Parsed once, up front: a bad file fails now rather than leaving every caller PC
+        // unresolved, and re-parsing it per PC would dominate a move.
+        let object_context = match &self.config.symbolizer {
+            Symbolizer::ObjectFile { path, base_address } => {
+                Some(Mutex::new(ObjectContext::load(path, *base_address)?))
+            }
+            Symbolizer::InProcess => None,
+        };
+        let audit_log = match &self.config.audit_log_path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|err| {
+                        NftPtrError::AuditLog(format!("can't open {}: {}", path.display(), err))
+                    })?,
+            )),
+            None => None,
+        };
+        Ok(NftPtrLib {
+            web3,
+            account: Address::zero(),
+            token_contracts: Vec::new(),
+            token_deploy_receipts: Vec::new(),
+            instance_to_contract: RwLock::new(HashMap::new()),
+            owner_templates: Vec::new(),
+            token_artifact,
+            owner_artifact,
+            config: self.config,
+            network_id: 0,
+            chain_id: None,
+            account_private_key,
+            dry_run_nonce: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            num_confirmations_override: AtomicU64::new(NO_CONFIRMATIONS_OVERRIDE),
+            tx_listener: None,
+            lifecycle_observer: None,
+            submitted_transactions: Mutex::new(Vec::new()),
+            latest_mined_block: Mutex::new(None),
+            stats: Mutex::new(Stats::default()),
+            history: Mutex::new(VecDeque::new()),
+            last_moves: Mutex::new(HashMap::new()),
+            caller_locations: Mutex::new(HashMap::new()),
+            object_context,
+            next_nonce: tokio::sync::Mutex::new(None),
+            pending_moves: Mutex::new(Vec::new()),
+            audit_log,
+        })
+    }
+}
+
+impl Default for NftPtrLibBuilder {
+    fn default() -> NftPtrLibBuilder {
+        NftPtrLibBuilder::new()
+    }
+}
+
+// The first account of the usual Ethereum wallet layout.
+pub const DEFAULT_DERIVATION_PATH: &str = "m/44'/60'/0'/0/0";
+
+// The private key at `derivation_path` (e.g. DEFAULT_DERIVATION_PATH) of the wallet for an
+// English BIP-39 mnemonic, with no passphrase.
+pub fn derive_key(mnemonic: &str, derivation_path: &str) -> Result<SecretKey, NftPtrError> {
+    let mnemonic = bip39::Mnemonic::from_phrase(mnemonic.trim(), bip39::Language::English)
+        .map_err(|err| NftPtrError::Keystore(format!("invalid mnemonic: {}", err)))?;
+    let seed = bip39::Seed::new(&mnemonic, "");
+    let key = tiny_hderive::bip32::ExtendedPrivKey::derive(seed.as_bytes(), derivation_path)
+        .map_err(|err| {
+            NftPtrError::Keystore(format!(
+                "invalid derivation path {:?}: {:?}",
+                derivation_path, err
+            ))
+        })?;
+    SecretKey::from_slice(&key.secret())
+        .map_err(|err| NftPtrError::Keystore(format!("invalid derived key: {}", err)))
+}
+
+// Parses a numeric NFT_PTR_* environment variable. A malformed value is logged and
+// treated as unset, so a typo falls back to the default instead of crashing the program.
+fn env_number<N: FromStr>(name: &str) -> Option<N> {
+    env_parsed(name, |value| value.parse().ok())
+}
+
+// env_number with its own parser, for numbers FromStr doesn't read the way we want.
+fn env_parsed<N>(name: &str, parse: impl FnOnce(&str) -> Option<N>) -> Option<N> {
+    let value = std::env::var(name).ok()?;
+    match parse(value.trim()) {
+        Some(number) => Some(number),
+        None => {
+            warn!("Ignoring {}={:?}: not a valid number", name, value);
+            None
+        }
+    }
+}
+
+// A comma-separated NFT_PTR_ALLOWED_NETWORK_IDS. Unlike the other numbers, a typo here is an
+// error: falling back would quietly drop the check on which network we may spend on.
+pub(crate) fn parse_network_ids(ids: &str) -> Result<HashSet<u32>, NftPtrError> {
+    ids.split(',')
+        .map(|id| {
+            id.trim().parse().map_err(|_| NftPtrError::BadEnvVar {
+                name: "NFT_PTR_ALLOWED_NETWORK_IDS",
+                value: ids.to_string(),
+            })
+        })
+        .collect()
+}
+
+// NFT_PTR_SYMBOL_FILE, loaded at NFT_PTR_SYMBOL_BASE (hex, default 0).
+fn env_symbolizer() -> Symbolizer {
+    let path = match std::env::var_os("NFT_PTR_SYMBOL_FILE") {
+        Some(path) => PathBuf::from(path),
+        None => return Symbolizer::InProcess,
+    };
+    let base_address = match std::env::var("NFT_PTR_SYMBOL_BASE") {
+        Ok(base) => {
+            u64::from_str_radix(base.trim().trim_start_matches("0x"), 16).unwrap_or_else(|_| {
+                warn!("Ignoring NFT_PTR_SYMBOL_BASE={:?}: not a hex address", base);
+                0
+            })
+        }
+        Err(_) => 0,
+    };
+    Symbolizer::ObjectFile { path, base_address }
+}
+
+// Parses a 32-byte hex private key, with or without a 0x prefix.
+pub(crate) fn parse_private_key(private_key: &str) -> Result<SecretKey, NftPtrError> {
+    let private_key = private_key.trim();
+    let hex_key = private_key.strip_prefix("0x").unwrap_or(private_key);
+    if hex_key.len() != 64 {
+        return Err(NftPtrError::Keystore(format!(
+            "private key should be 64 hex digits, got {}",
+            hex_key.len()
+        )));
+    }
+    let bytes = hex::decode(hex_key)
+        .map_err(|err| NftPtrError::Keystore(format!("invalid private key: {}", err)))?;
+    SecretKey::from_slice(&bytes)
+        .map_err(|err| NftPtrError::Keystore(format!("invalid private key: {}", err)))
+}
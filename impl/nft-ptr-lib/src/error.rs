@@ -0,0 +1,278 @@
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+use web3::ethabi;
+use web3::types::{Address, H256, U256};
+
+// Selector for Error(string), which require() and revert() encode their message with.
+const REVERT_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+#[derive(Debug)]
+pub enum NftPtrError {
+    Web3(web3::Error),
+    Abi(web3::ethabi::Error),
+    Contract(web3::contract::Error),
+    // A contract deploy didn't leave a contract behind, e.g. no code at its address.
+    ContractDeploy(String),
+    // A contract deploy was mined, but reverted.
+    DeployReverted {
+        transaction_hash: H256,
+        gas_used: Option<U256>,
+    },
+    // A contract bytecode/ABI file that can't be read or parsed.
+    Artifact(String),
+    // A transaction reverted, or the node refused it because it would, with the Solidity
+    // revert message if we could get it.
+    Reverted {
+        reason: Option<String>,
+    },
+    // NFT_PTR_KEYSTORE points at a file we couldn't read.
+    KeystoreRead(String, std::io::Error),
+    // NFT_PTR_KEYSTORE is set without NFT_PTR_PASSWORD.
+    PasswordMissing,
+    // Usually a wrong password.
+    KeystoreDecrypt(String),
+    // A private key that isn't usable, or conflicting key settings.
+    Keystore(String),
+    // The RPC URL (e.g. from NFT_PTR_HTTP) isn't a valid http(s) URL.
+    BadEndpoint(String),
+    InsufficientFunds {
+        account: Address,
+        balance: U256,
+    },
+    // An NFT_PTR_* environment variable guarding a safety check is malformed, so it can't
+    // just be ignored like the others.
+    BadEnvVar {
+        name: &'static str,
+        value: String,
+    },
+    // Connected to mainnet, either without force_allow_fork or with a client_version that
+    // doesn't look like a local fork.
+    MainnetRefused {
+        client_version: Option<String>,
+    },
+    // Connected to a network id that isn't in the configured allow-list.
+    DisallowedNetwork(u32),
+    // The node didn't answer an RPC request within the configured rpc_timeout, or a
+    // transaction wasn't confirmed within request_timeout.
+    Timeout(Duration),
+    // The node is on a different network than the configured expected_chain_id.
+    UnexpectedChain {
+        expected: u32,
+        actual: u32,
+    },
+    // The operation needs to sign transactions itself, but the node is doing the signing.
+    LocalSigningRequired(&'static str),
+    // The node doesn't have an unlocked account at the configured account_index.
+    NoAccounts {
+        account_index: usize,
+        num_accounts: usize,
+    },
+    // A state file from save_state/load_state (or an export_history file) that can't be
+    // read, written or parsed.
+    State(String),
+    // The audit log file can't be opened.
+    AuditLog(String),
+    // move_token was given an owner address that isn't a tracked nft_ptr, with
+    // OwnerFallback::ErrorOnUnknown.
+    UntrackedPointer(u64),
+    // ptr_initialize was called while paused (set_paused), so no owner contract was deployed.
+    // Not a failure as such; the nft_ptr just isn't tracked.
+    Paused,
+    // The worker from spawn_worker isn't running anymore (it panicked).
+    WorkerStopped,
+    // Something that needs the token contract was called before initialize or load_state.
+    NotInitialized,
+    // The object file for Symbolizer::ObjectFile can't be read or has no usable debug info.
+    Symbolizer(String),
+}
+
+impl fmt::Display for NftPtrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NftPtrError::Web3(err) => write!(f, "web3 error: {}", err),
+            NftPtrError::Abi(err) => write!(f, "abi error: {}", err),
+            NftPtrError::Contract(err) => write!(f, "contract error: {}", err),
+            NftPtrError::ContractDeploy(msg) => write!(f, "contract deploy failed: {}", msg),
+            NftPtrError::DeployReverted {
+                transaction_hash,
+                gas_used: Some(gas_used),
+            } => write!(
+                f,
+                "contract deploy {:#x} reverted (gas used: {})",
+                transaction_hash, gas_used
+            ),
+            NftPtrError::DeployReverted {
+                transaction_hash,
+                gas_used: None,
+            } => write!(f, "contract deploy {:#x} reverted", transaction_hash),
+            NftPtrError::Artifact(msg) => write!(f, "bad contract artifact: {}", msg),
+            NftPtrError::Reverted {
+                reason: Some(reason),
+            } => write!(f, "transaction reverted: {}", reason),
+            NftPtrError::Reverted { reason: None } => write!(f, "transaction reverted"),
+            NftPtrError::KeystoreRead(path, err) => {
+                write!(f, "can't read keystore {}: {}", path, err)
+            }
+            NftPtrError::PasswordMissing => {
+                write!(f, "NFT_PTR_KEYSTORE is set, but NFT_PTR_PASSWORD isn't")
+            }
+            NftPtrError::KeystoreDecrypt(msg) => write!(
+                f,
+                "can't decrypt keystore (is NFT_PTR_PASSWORD right?): {}",
+                msg
+            ),
+            NftPtrError::Keystore(msg) => write!(f, "signing key error: {}", msg),
+            NftPtrError::BadEndpoint(url) => write!(f, "invalid RPC endpoint: {}", url),
+            NftPtrError::InsufficientFunds { account, balance } => write!(
+                f,
+                "account {} has a balance of {} wei; fund it before deploying",
+                crate::checksum(account),
+                balance
+            ),
+            NftPtrError::BadEnvVar { name, value } => write!(f, "invalid {}={:?}", name, value),
+            NftPtrError::MainnetRefused {
+                client_version: None,
+            } => write!(
+                f,
+                "cowardly refusing to run on mainnet and waste real \"money\""
+            ),
+            NftPtrError::MainnetRefused {
+                client_version: Some(client_version),
+            } => write!(
+                f,
+                "cowardly refusing to run on mainnet: force_allow_fork is set, but {} doesn't \
+                 look like a local fork",
+                client_version
+            ),
+            NftPtrError::DisallowedNetwork(id) => {
+                write!(f, "network id {} is not in the allowed network ids", id)
+            }
+            NftPtrError::Timeout(timeout) => {
+                write!(f, "timed out after {:?}", timeout)
+            }
+            NftPtrError::UnexpectedChain { expected, actual } => write!(
+                f,
+                "expected network id {}, but the node is on network id {}",
+                expected, actual
+            ),
+            NftPtrError::LocalSigningRequired(operation) => write!(
+                f,
+                "{} needs a local signing key (NFT_PTR_KEYSTORE or NFT_PTR_PRIVATE_KEY)",
+                operation
+            ),
+            NftPtrError::NoAccounts {
+                account_index,
+                num_accounts,
+            } if *num_accounts == 0 => write!(
+                f,
+                "the node has no unlocked accounts; unlock one, or sign locally by setting \
+                 NFT_PTR_PRIVATE_KEY or NFT_PTR_KEYSTORE (account index {} was requested)",
+                account_index
+            ),
+            NftPtrError::NoAccounts {
+                account_index,
+                num_accounts,
+            } => write!(
+                f,
+                "no account at index {}: the node has {} unlocked accounts",
+                account_index, num_accounts
+            ),
+            NftPtrError::State(msg) => write!(f, "saved state error: {}", msg),
+            NftPtrError::AuditLog(msg) => write!(f, "audit log error: {}", msg),
+            NftPtrError::UntrackedPointer(address) => write!(
+                f,
+                "{:#x} isn't a tracked nft_ptr (was ptr_initialize called for it?)",
+                address
+            ),
+            NftPtrError::Paused => write!(f, "paused, so the nft_ptr isn't tracked"),
+            NftPtrError::WorkerStopped => write!(f, "the background worker has stopped"),
+            NftPtrError::NotInitialized => write!(
+                f,
+                "no token contract yet (call initialize or load_state first)"
+            ),
+            NftPtrError::Symbolizer(msg) => write!(f, "can't load symbols: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for NftPtrError {}
+
+impl NftPtrError {
+    // Turns an error from sending a transaction into Reverted if the node says it reverted.
+    pub(crate) fn from_send_error(err: web3::Error) -> NftPtrError {
+        if let web3::Error::Rpc(rpc_err) = &err {
+            let reason = rpc_err.data.as_ref().and_then(find_revert_reason);
+            if reason.is_some() {
+                return NftPtrError::Reverted { reason };
+            }
+            // Ganache: "VM Exception while processing transaction: revert <reason>"
+            if let Some(index) = rpc_err.message.find("revert") {
+                let reason = rpc_err.message[index + "revert".len()..].trim();
+                return NftPtrError::Reverted {
+                    reason: if reason.is_empty() {
+                        None
+                    } else {
+                        Some(reason.to_string())
+                    },
+                };
+            }
+        }
+        NftPtrError::Web3(err)
+    }
+
+    // Whether the node refused a transaction over its nonce, i.e. our local count is off
+    // (another sender used the account, or the node dropped its pending transactions).
+    pub(crate) fn is_nonce_error(&self) -> bool {
+        match self {
+            NftPtrError::Web3(web3::Error::Rpc(rpc_err)) => {
+                let message = rpc_err.message.to_lowercase();
+                message.contains("nonce too low")
+                    || message.contains("nonce too high")
+                    || message.contains("invalid nonce")
+            }
+            _ => false,
+        }
+    }
+}
+
+// Decodes the message from ABI-encoded Error(string) revert data.
+pub(crate) fn decode_revert_reason(data: &[u8]) -> Option<String> {
+    if data.len() < 4 || data[..4] != REVERT_SELECTOR {
+        return None;
+    }
+    ethabi::decode(&[ethabi::ParamType::String], &data[4..])
+        .ok()?
+        .into_iter()
+        .next()?
+        .into_string()
+}
+
+// Geth puts the revert data directly in the error's data field as a hex string;
+// Ganache nests it in an object keyed by transaction hash.
+fn find_revert_reason(value: &Value) -> Option<String> {
+    match value {
+        Value::String(data) => decode_revert_reason(&hex::decode(data.strip_prefix("0x")?).ok()?),
+        Value::Object(map) => map.values().find_map(find_revert_reason),
+        Value::Array(values) => values.iter().find_map(find_revert_reason),
+        _ => None,
+    }
+}
+
+impl From<web3::Error> for NftPtrError {
+    fn from(err: web3::Error) -> NftPtrError {
+        NftPtrError::Web3(err)
+    }
+}
+
+impl From<web3::ethabi::Error> for NftPtrError {
+    fn from(err: web3::ethabi::Error) -> NftPtrError {
+        NftPtrError::Abi(err)
+    }
+}
+
+impl From<web3::contract::Error> for NftPtrError {
+    fn from(err: web3::contract::Error) -> NftPtrError {
+        NftPtrError::Contract(err)
+    }
+}
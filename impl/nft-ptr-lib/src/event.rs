@@ -0,0 +1,133 @@
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+use web3::types::{Address, H256, U256};
+
+// Passed to the listener set with NftPtrLib::set_tx_listener for every transaction we send.
+#[derive(Clone, Debug, Serialize)]
+pub struct TxEvent {
+    pub transaction_hash: H256,
+    // From the receipt; None if the node left it out, or in a dry run.
+    pub gas_used: Option<U256>,
+    pub kind: TxKind,
+}
+
+// Passed to the observer set with NftPtrLib::set_lifecycle_observer as a move_token or
+// ptr_initialize transaction goes through each stage.
+#[derive(Clone, Debug, Serialize)]
+pub struct LifecycleEvent {
+    pub operation: TxOperation,
+    pub stage: TxStage,
+}
+
+// What a transaction seen by the lifecycle observer is for.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum TxOperation {
+    MoveToken {
+        value: u64,
+        owner_address: u64,
+        previous_owner_address: u64,
+    },
+    PtrInitialize {
+        owner_address: u64,
+        // Demangled.
+        object_type: String,
+    },
+}
+
+// Submitted, then Mined, then Confirmed or Failed. A transaction the node refuses goes
+// straight to Failed, without a hash.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type")]
+pub enum TxStage {
+    Submitted {
+        transaction_hash: H256,
+    },
+    // In a block, but maybe not yet confirmed by the confirmation policy.
+    Mined {
+        transaction_hash: H256,
+        block_number: u64,
+    },
+    Confirmed {
+        transaction_hash: H256,
+        gas_used: Option<U256>,
+    },
+    Failed {
+        transaction_hash: Option<H256>,
+        error: String,
+    },
+}
+
+// One transaction in NftPtrLib::history.
+#[derive(Clone, Debug, Serialize)]
+pub struct HistoryEntry {
+    // When it was mined, by the lib's clock.
+    pub timestamp_millis: u64,
+    pub transaction_hash: H256,
+    pub gas_used: Option<U256>,
+    pub kind: TxKind,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum TxKind {
+    DeployToken {
+        contract: Address,
+    },
+    PtrInitialize {
+        owner_address: u64,
+        contract: Address,
+        // Demangled, so gas use can be broken down by type.
+        object_type: String,
+    },
+    MoveToken {
+        value: u64,
+        owner_address: u64,
+        previous_owner_address: u64,
+        owner_contract: Address,
+        previous_owner_contract: Address,
+        object_type: String,
+        caller: CallerLocation,
+    },
+    SetApprovalForAll {
+        operator: Address,
+        approved: bool,
+    },
+    BurnToken {
+        value: u64,
+    },
+    // Also written to the audit log when the owner contract isn't destroyed on-chain, with
+    // no transaction.
+    PtrDestroy {
+        owner_address: u64,
+        contract: Address,
+    },
+}
+
+// Where a move came from: the caller PC passed to move_token, resolved against the
+// instrumented program's debug info. Everything but `pc` is None if it couldn't be resolved.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CallerLocation {
+    pub pc: u64,
+    // The demangled function name.
+    pub symbol: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+// The form passed to mintOrMove: "function (file.cpp:12)", or the PC in hex if unresolved.
+impl fmt::Display for CallerLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.symbol, &self.file, self.line) {
+            (Some(symbol), Some(file), Some(line)) => {
+                let file_name = Path::new(file)
+                    .file_name()
+                    .map_or_else(|| file.clone(), |name| name.to_string_lossy().into_owned());
+                write!(f, "{} ({}:{})", symbol, file_name, line)
+            }
+            (Some(symbol), _, _) => write!(f, "{}", symbol),
+            (None, _, _) => write!(f, "{:x}", self.pc),
+        }
+    }
+}
@@ -1,381 +1,4335 @@
-use log::info;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::future::Future;
+use std::io::Write;
+use std::panic::AssertUnwindSafe;
 use std::path::Path;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, instrument, warn};
 use web3::api::Web3;
-use web3::contract::Contract;
-use web3::signing::Key;
-use web3::types::{Address, U256};
+use web3::contract::tokens::Tokenize;
+use web3::contract::{Contract, Options};
+use web3::ethabi;
+use web3::futures::future::join_all;
+use web3::signing::{Key, SecretKeyRef};
+use web3::types::{
+    Address, BlockId, BlockNumber, Bytes, CallRequest, FilterBuilder, SyncState, TransactionId,
+    TransactionParameters, TransactionReceipt, TransactionRequest, H256, U256, U64,
+};
+
+mod artifact;
+mod builder;
+mod error;
+mod event;
+#[cfg(any(test, feature = "test-util"))]
+mod mock;
+mod state;
+mod symbolizer;
+mod transport;
+mod worker;
+use artifact::Artifact;
+use builder::Config;
+pub use builder::{
+    derive_key, ConfirmationPolicy, DemangleMode, GasStrategy, NftPtrLibBuilder, OwnerFallback,
+    Verbosity, DEFAULT_DERIVATION_PATH,
+};
+pub use error::NftPtrError;
+pub use event::{
+    CallerLocation, HistoryEntry, LifecycleEvent, TxEvent, TxKind, TxOperation, TxStage,
+};
+#[cfg(any(test, feature = "test-util"))]
+pub use mock::MockTransport;
+use state::SavedState;
+use symbolizer::ObjectContext;
+pub use symbolizer::Symbolizer;
+pub use transport::{CloseTransport, ReconnectingIpc};
+pub use worker::WorkerHandle;
 
 const NUM_CONFIRMATIONS: usize = 0;
+// NftPtrLib::num_confirmations_override before set_num_confirmations is called.
+const NO_CONFIRMATIONS_OVERRIDE: u64 = u64::MAX;
+const DEFAULT_HTTP_ENDPOINT: &str = "http://127.0.0.1:7545";
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How many times to look for the token contract's code after deploying it.
+const CODE_CHECK_ATTEMPTS: usize = 5;
+const DEFAULT_RPC_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_INIT_CONCURRENCY: usize = 8;
+const DEFAULT_HISTORY_LIMIT: usize = 10_000;
+// ERC165 interface id for ERC721Enumerable.
+const ERC721_ENUMERABLE_INTERFACE_ID: [u8; 4] = [0x78, 0x0e, 0x9d, 0x63];
+const LOG_PAGE_BLOCKS: u64 = 5_000;
+// Ceiling for scaled hardcoded gas limits: mainnet's block gas limit, so a bad
+// gas_buffer_multiplier can't produce a transaction no block could include.
+const MAX_GAS_LIMIT: u64 = 30_000_000;
+// {contract} and {chain} in the base URI are replaced with the token contract's address
+// and the network id, so a metadata server can tell collections apart.
 const TOKEN_BASE_URI: &str = "https://nft-ptr.notnow.dev/?";
 
+// Logs a message that's emitted once per move or nft_ptr: at info with Verbosity::Verbose,
+// otherwise at debug. tracing needs the level at compile time, hence the macro.
+macro_rules! detail {
+    ($lib:expr, $($arg:tt)+) => {
+        if $lib.config.verbosity == Verbosity::Verbose {
+            info!($($arg)+)
+        } else {
+            debug!($($arg)+)
+        }
+    };
+}
+
+// Threading: initialize and the other `&mut self` setup methods (register_owner_template,
+// set_tx_listener) must be called before the lib is shared. Everything else takes `&self`,
+// so an `Arc<NftPtrLib>` can track pointers from many tasks at once: transactions are handed
+// to the node one at a time, so each gets the next nonce, but waiting for them to be mined
+// happens concurrently, and lookups like resolve_owner never wait behind a send.
 pub struct NftPtrLib<T: web3::Transport> {
     web3: Web3<T>,
     pub account: Address,
-    token_contract: Option<Contract<T>>,
-    instance_to_contract: HashMap<u64, Contract<T>>,
-    num_confirmations: usize,
+    // One token contract per shard (config.token_shards); the token for `value` lives on
+    // shard value % token_contracts.len(). Empty until initialize or load_state.
+    token_contracts: Vec<Contract<T>>,
+    // The deploy receipts of token_contracts, if we deployed them this run.
+    token_deploy_receipts: Vec<TransactionReceipt>,
+    // nft_ptr address -> the owner contracts ptr_initialize deployed for it, most recent
+    // last. Normally just one; more if the address was initialized again before being
+    // destroyed, e.g. by a pool allocator reusing it. Never holds an empty Vec.
+    instance_to_contract: RwLock<HashMap<u64, Vec<Contract<T>>>>,
+    owner_templates: Vec<OwnerTemplate>,
+    token_artifact: Artifact,
+    owner_artifact: Artifact,
+    config: Config,
     network_id: u32,
-    use_hardcoded_gas: bool,
+    // Fetched once by initialize when we sign locally or config.replay_protected is set.
+    chain_id: Option<u64>,
     account_private_key: Option<secp256k1::SecretKey>,
+    // Counter for the fake contract addresses handed out in dry-run mode.
+    dry_run_nonce: AtomicU64,
+    paused: AtomicBool,
+    // From set_num_confirmations, replacing both confirmation policies in config;
+    // NO_CONFIRMATIONS_OVERRIDE until then.
+    num_confirmations_override: AtomicU64,
+    tx_listener: Option<Box<dyn Fn(&TxEvent) + Send + Sync>>,
+    lifecycle_observer: Option<Box<dyn Fn(&LifecycleEvent) + Send + Sync>>,
+    // Sent, but not yet seen mined. barrier waits for these to be confirmed.
+    submitted_transactions: Mutex<Vec<H256>>,
+    // The newest block one of our transactions was seen mined in since the last barrier,
+    // which then only has to wait for that block to be deep enough.
+    latest_mined_block: Mutex<Option<U64>>,
+    stats: Mutex<Stats>,
+    // The most recent config.history_limit entries, oldest first.
+    history: Mutex<VecDeque<HistoryEntry>>,
+    // Token value -> the owner contract we last moved it to, for config.dedup_moves.
+    last_moves: Mutex<HashMap<u64, Address>>,
+    // Caller PC -> its resolved location and that formatted, since a hot call site shows up
+    // in thousands of moves and symbolizing it is the slow part.
+    caller_locations: Mutex<HashMap<u64, (CallerLocation, String)>>,
+    // The debug info for Symbolizer::ObjectFile; None for Symbolizer::InProcess.
+    object_context: Option<Mutex<ObjectContext>>,
+    // The nonce for our next transaction, or None to ask the node. Locked for the whole
+    // submission of a transaction; see send_transaction.
+    next_nonce: tokio::sync::Mutex<Option<U256>>,
+    // Moves sent in fire_and_forget mode that flush hasn't checked yet.
+    pending_moves: Mutex<Vec<PendingMove>>,
+    // Opened in append mode from config.audit_log_path.
+    audit_log: Option<Mutex<File>>,
+}
+
+struct PendingMove {
+    transaction_hash: H256,
+    contract: Address,
+    data: Vec<u8>,
+    kind: TxKind,
+    operation: TxOperation,
+}
+
+#[derive(Default)]
+struct Stats {
+    moves: usize,
+    deploys: usize,
+    gas_used: U256,
+}
+
+// One ERC721 Transfer event from the token contract. Mints come from the zero address.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferRecord {
+    pub from: Address,
+    pub to: Address,
+    pub token_id: U256,
+    pub block: U64,
+    pub transaction_hash: H256,
+}
+
+// One nft_ptr for ptr_initialize_many; the same arguments ptr_initialize takes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PtrInitRequest {
+    pub owner_address: u64,
+    pub caller_pc: u64,
+    pub ptr_object_type: String,
+}
+
+// A tracked nft_ptr, as returned by ptr_initialize. The lib's own map stays the source of
+// truth; this just saves callers from looking the owner contract up again. Dropping it
+// doesn't destroy anything (Drop can't send transactions), so hand it to ptr_destroy_token
+// when the nft_ptr goes away.
+#[derive(Debug, PartialEq)]
+pub struct PtrToken {
+    pub owner_address: u64,
+    pub contract: Address,
+    pub receipt: TransactionReceipt,
+}
+
+// What health_check found out about the node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthInfo {
+    pub network_id: u32,
+    pub block_number: U64,
+    // Still catching up with the chain, so reads may be stale.
+    pub syncing: bool,
+    // None if the node doesn't support net_peerCount (e.g. Ganache or a hosted RPC).
+    pub peer_count: Option<u32>,
+    // The account transactions would be sent from.
+    pub account: Address,
+    pub account_balance: U256,
+}
+
+// The settings an NftPtrLib ended up with, from the builder and the NFT_PTR_* environment
+// variables; see NftPtrLib::config. network_id, chain_id and account are only known after
+// initialize.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LibConfig {
+    pub network_id: u32,
+    // Only fetched when signing locally or replay_protected is set.
+    pub chain_id: Option<u64>,
+    pub account: Address,
+    pub signs_locally: bool,
+    pub deploy_confirmation_policy: ConfirmationPolicy,
+    pub call_confirmation_policy: ConfirmationPolicy,
+    pub use_hardcoded_gas: bool,
+    pub gas_buffer_multiplier: f64,
+    pub gas_price_multiplier: f64,
+    pub dry_run: bool,
+    pub fire_and_forget: bool,
+    pub replay_protected: bool,
+    pub resync_nonce_on_error: bool,
+    pub access_lists: bool,
+    pub destroy_owner_contracts: bool,
+    pub verbosity: Verbosity,
+    pub demangle_mode: DemangleMode,
+    pub owner_fallback: OwnerFallback,
+    pub token_shards: usize,
+    pub history_limit: usize,
+    pub run_id: Option<String>,
+    pub rpc_timeout: Duration,
+    pub request_timeout: Option<Duration>,
+}
+
+// Where a transaction we sent has got to; see NftPtrLib::transaction_status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxStatus {
+    // Known to the node but not mined yet.
+    Pending,
+    // Mined in `block`; `success` is false if it reverted.
+    Mined { block: U64, success: bool },
+    // The node has never heard of it, or has forgotten it (e.g. replaced or evicted).
+    Dropped,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OwnerResolution {
+    // The owner contract ptr_initialize deployed for this nft_ptr.
+    Tracked(Address),
+    // Address 0, i.e. no nft_ptr: tokens minted from or burned to nowhere belong to our account.
+    DefaultAccount(Address),
+    // Not an nft_ptr we're tracking (never initialized, or already destroyed).
+    Unknown,
+}
+
+impl OwnerResolution {
+    // The zero address for Unknown, which has no owner.
+    pub fn address(&self) -> Address {
+        match self {
+            OwnerResolution::Tracked(address) | OwnerResolution::DefaultAccount(address) => {
+                *address
+            }
+            OwnerResolution::Unknown => Address::zero(),
+        }
+    }
+}
+
+// Where move_token sends tokens for an nft_ptr: resolve_owner with config.owner_fallback
+// applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OwnerContract {
+    // The owner contract ptr_initialize deployed for this nft_ptr.
+    Tracked(Address),
+    // Not a tracked nft_ptr: our account for address 0, otherwise wherever
+    // config.owner_fallback says.
+    Fallback(Address),
+}
+
+impl OwnerContract {
+    pub fn address(&self) -> Address {
+        match self {
+            OwnerContract::Tracked(address) | OwnerContract::Fallback(address) => *address,
+        }
+    }
+}
+
+// An alternative owner contract, deployed instead of NftPtrOwner for nft_ptrs whose
+// demangled type contains `pattern`. Its constructor must take the same (string name) argument.
+struct OwnerTemplate {
+    pattern: String,
+    artifact: Artifact,
 }
 
 impl<T: web3::Transport> NftPtrLib<T> {
+    // Panics if the keystore can't be loaded; see try_new.
     pub fn new(transport: T) -> NftPtrLib<T> {
-        let web3 = web3::Web3::new(transport);
-        let num_confirmations = std::env::var("NFT_PTR_NUM_CONFIRMATIONS")
-            .map(|a| a.parse::<usize>().unwrap())
-            .unwrap_or(NUM_CONFIRMATIONS);
-        let account_private_key = if let Ok(keystore_path) = std::env::var("NFT_PTR_KEYSTORE") {
-            let keystore_str = std::fs::read_to_string(keystore_path).unwrap();
-            let password = std::env::var("NFT_PTR_PASSWORD").unwrap();
-            let keystore =
-                keystore_loader::load_keystore_from_string(&keystore_str, &password).unwrap();
-            Some(keystore)
-        } else {
-            None
-        };
-        NftPtrLib {
-            web3,
-            account: Address::zero(),
-            token_contract: None,
-            instance_to_contract: HashMap::new(),
-            num_confirmations,
-            network_id: 0,
-            use_hardcoded_gas: std::env::var("NFT_PTR_NO_HARDCODED_GAS").is_err(),
-            account_private_key,
-        }
-    }
-    pub async fn initialize(&mut self) {
-        self.check_not_prod().await;
-        if self.account_private_key.is_none() {
-            self.account = self.web3.eth().accounts().await.unwrap()[0];
+        NftPtrLib::from_web3(Web3::new(transport))
+    }
+    // For apps that already have a configured Web3 client to share. Panics like new.
+    pub fn from_web3(web3: Web3<T>) -> NftPtrLib<T> {
+        NftPtrLibBuilder::new().build_with_web3(web3).unwrap()
+    }
+    pub fn try_new(transport: T) -> Result<NftPtrLib<T>, NftPtrError> {
+        NftPtrLibBuilder::new().build(transport)
+    }
+    #[instrument(skip(self), fields(network_id, account))]
+    pub async fn initialize(&mut self) -> Result<(), NftPtrError> {
+        self.check_not_prod().await?;
+        tracing::Span::current().record("network_id", &self.network_id);
+        self.account = self.resolve_account().await?;
+        tracing::Span::current().record("account", &tracing::field::debug(self.account));
+        info!("Account: {}", checksum(&self.account));
+        if self.config.replay_protected || self.signing_key().is_some() {
+            self.chain_id = Some(self.rpc(self.web3.eth().chain_id()).await?.as_u64());
+        }
+        if self.config.replay_protected && self.signing_key().is_none() {
+            warn!(
+                "The node signs our transactions, so it decides whether they're \
+                 replay-protected"
+            );
+        }
+        if self.config.access_lists && self.signing_key().is_some() {
+            warn!("Access lists are only attached to transactions the node signs");
+        }
+        if let Some(url) = self.etherscan_address_url(self.account) {
+            info!("{}", url);
+        }
+        if !self.config.dry_run {
+            self.check_balance().await?;
+        }
+        if !self.token_contracts.is_empty() {
+            for contract in &self.token_contracts {
+                info!(
+                    "Resuming with token contract {}",
+                    checksum(&contract.address())
+                );
+            }
         } else {
-            self.account =
-                web3::signing::SecretKeyRef::new(&self.account_private_key.unwrap()).address();
+            info!("Deploying NFT contract!");
+            for _ in 0..self.config.token_shards.max(1) {
+                self.deploy_token_contract().await?;
+                let receipt = self.token_deploy_receipts.last().unwrap();
+                info!(
+                    tx_hash = ?receipt.transaction_hash,
+                    "Token contract deployed at {} (gas used: {})",
+                    checksum(&self.token_contracts.last().unwrap().address()),
+                    format_gas_used(receipt)
+                );
+            }
+            if !self.config.preset_tokens.is_empty() {
+                info!("Minting {} preset tokens", self.config.preset_tokens.len());
+            }
+            // Minted to our own account, like any token whose owner isn't a tracked nft_ptr.
+            for (value, object_type) in &self.config.preset_tokens {
+                self.move_token(0, 0, *value, 0, object_type).await?;
+            }
+        }
+        for contract in &self.token_contracts {
+            if let Some(url) = self.etherscan_token_url(contract.address()) {
+                info!("{}", url);
+            }
         }
-        info!("Account: {:#x}", self.account);
-        if self.is_goerli() {
-            info!("https://goerli.etherscan.io/address/{:#x}", self.account);
+        Ok(())
+    }
+    // The account we send from: the local signing key's, or else the node's account at
+    // config.account_index.
+    async fn resolve_account(&self) -> Result<Address, NftPtrError> {
+        if let Some(key) = self.signing_key() {
+            return Ok(key.address());
         }
-        info!("Deploying NFT contract!");
-        self.deploy_token_contract().await;
-        info!(
-            "Token contract deployed at {:#x}",
-            self.token_contract.as_ref().unwrap().address()
+        let accounts = self.rpc(self.web3.eth().accounts()).await?;
+        accounts
+            .get(self.config.account_index)
+            .copied()
+            .ok_or(NftPtrError::NoAccounts {
+                account_index: self.config.account_index,
+                num_accounts: accounts.len(),
+            })
+    }
+
+    // A snapshot of the effective configuration, e.g. to log at startup.
+    pub fn config(&self) -> LibConfig {
+        LibConfig {
+            network_id: self.network_id,
+            chain_id: self.chain_id,
+            account: self.account,
+            signs_locally: self.account_private_key.is_some(),
+            deploy_confirmation_policy: self.deploy_confirmation_policy(),
+            call_confirmation_policy: self.call_confirmation_policy(),
+            use_hardcoded_gas: self.config.use_hardcoded_gas,
+            gas_buffer_multiplier: self.config.gas_buffer_multiplier,
+            gas_price_multiplier: self.config.gas_price_multiplier,
+            dry_run: self.config.dry_run,
+            fire_and_forget: self.config.fire_and_forget,
+            replay_protected: self.config.replay_protected,
+            resync_nonce_on_error: self.config.resync_nonce_on_error,
+            access_lists: self.config.access_lists,
+            destroy_owner_contracts: self.config.destroy_owner_contracts,
+            verbosity: self.config.verbosity,
+            demangle_mode: self.config.demangle_mode,
+            owner_fallback: self.config.owner_fallback,
+            token_shards: self.config.token_shards,
+            history_limit: self.config.history_limit,
+            run_id: self.config.run_id.clone(),
+            rpc_timeout: self.config.rpc_timeout,
+            request_timeout: self.config.request_timeout,
+        }
+    }
+
+    // Checks that the node is reachable, and reports what it's connected to, without
+    // sending anything. Can be called before initialize, e.g. by a supervisor vetting the
+    // RPC endpoint before starting the instrumented program.
+    #[instrument(skip(self))]
+    pub async fn health_check(&self) -> Result<HealthInfo, NftPtrError> {
+        let network_id = self.network_id_from_node().await?;
+        let block_number = self.rpc(self.web3.eth().block_number()).await?;
+        let syncing = !matches!(
+            self.rpc(self.web3.eth().syncing()).await?,
+            SyncState::NotSyncing
         );
-        if self.is_goerli() {
-            info!(
-                "https://goerli.etherscan.io/token/{:#x}",
-                self.token_contract.as_ref().unwrap().address()
-            );
+        let peer_count = self
+            .rpc(self.web3.net().peer_count())
+            .await
+            .ok()
+            .and_then(|count| u32::from_str_radix(count.trim_start_matches("0x"), 16).ok());
+        let account = self.resolve_account().await?;
+        let account_balance = self.rpc(self.web3.eth().balance(account, None)).await?;
+        Ok(HealthInfo {
+            network_id,
+            block_number,
+            syncing,
+            peer_count,
+            account,
+            account_balance,
+        })
+    }
+
+    // The underlying client, for raw calls the library doesn't wrap (e.g. reading a storage
+    // slot, or a view on an owner contract). Transactions sent through it bypass our nonce
+    // tracking, gas settings, dry run and history, so a send can clash with the library's
+    // next one; prefer it for reads.
+    pub fn web3(&self) -> &Web3<T> {
+        &self.web3
+    }
+    // The first token contract's deploy receipt, if initialize deployed it.
+    pub fn token_deploy_receipt(&self) -> Option<&TransactionReceipt> {
+        self.token_deploy_receipts.first()
+    }
+    // The token contract (shard) that the token for `value` lives on.
+    fn token_contract_for(&self, value: u64) -> Result<&Contract<T>, NftPtrError> {
+        if self.token_contracts.is_empty() {
+            return Err(NftPtrError::NotInitialized);
         }
+        Ok(&self.token_contracts[(value % self.token_contracts.len() as u64) as usize])
     }
-    async fn check_not_prod(&mut self) {
-        let version = self.web3.net().version().await.unwrap();
-        info!("Connected to network id {}", version);
-        if version == "1" {
-            panic!("Cowardly refusing to run on mainnet and waste real \"money\"");
+    // Catch "forgot to fund the account" before the deploy fails with an opaque revert.
+    async fn check_balance(&self) -> Result<(), NftPtrError> {
+        let balance = self
+            .rpc(self.web3.eth().balance(self.account, None))
+            .await?;
+        info!("Balance: {} wei", balance);
+        if balance.is_zero() {
+            return Err(NftPtrError::InsufficientFunds {
+                account: self.account,
+                balance,
+            });
         }
-        self.network_id = version.parse::<u32>().unwrap();
+        Ok(())
     }
-    async fn deploy_token_contract(&mut self) {
-        // rust-web3/examples/contract.rs
-        // TODO(zhuowei): understand this
-        let my_account = self.account;
-        let bytecode = include_str!("../../../contracts/out/NftPtrToken.code");
-        let contract_builder = Contract::deploy(
-            self.web3.eth(),
-            include_bytes!("../../../contracts/out/NftPtrToken.json"),
-        )
-        .unwrap()
-        .confirmations(self.num_confirmations)
-        .options(web3::contract::Options::with(|opt| {
-            // TODO(zhuowei): why does leaving this uncommented give me
-            // "VM Exception while processing transaction: revert"
-            //opt.value = Some(5.into());
-            //opt.gas_price = Some(5.into());
-            if self.use_hardcoded_gas {
-                opt.gas = Some(6_000_000.into());
-            }
-        }));
+    // net_version, which a misbehaving node (or a proxy's error page) may not answer with a
+    // number.
+    async fn network_id_from_node(&self) -> Result<u32, NftPtrError> {
+        let version = self.rpc(self.web3.net().version()).await?;
+        version.parse().map_err(|_| {
+            web3::Error::Decoder(format!("net_version isn't a number: {:?}", version)).into()
+        })
+    }
+    async fn check_not_prod(&mut self) -> Result<(), NftPtrError> {
+        let network_id = self.network_id_from_node().await?;
+        info!("Connected to network id {}", network_id);
+        if let Some(expected) = self.config.expected_chain_id {
+            if network_id != expected {
+                return Err(NftPtrError::UnexpectedChain {
+                    expected,
+                    actual: network_id,
+                });
+            }
+        }
+        match &self.config.allowed_network_ids {
+            Some(allowed_network_ids) => {
+                if !allowed_network_ids.contains(&network_id) {
+                    return Err(NftPtrError::DisallowedNetwork(network_id));
+                }
+            }
+            None => {
+                if network_id == 1 {
+                    if !self.config.force_allow_fork {
+                        return Err(NftPtrError::MainnetRefused {
+                            client_version: None,
+                        });
+                    }
+                    let client_version = self.rpc(self.web3.web3().client_version()).await?;
+                    if !is_dev_node(&client_version) {
+                        return Err(NftPtrError::MainnetRefused {
+                            client_version: Some(client_version),
+                        });
+                    }
+                    warn!("Running on a local mainnet fork ({})", client_version);
+                }
+            }
+        }
+        self.network_id = network_id;
+        Ok(())
+    }
+    async fn deploy_token_contract(&mut self) -> Result<(), NftPtrError> {
+        // TODO(zhuowei): why does setting opt.value = Some(5.into()) give me
+        // "VM Exception while processing transaction: revert"
+        let mut options = self.tx_options(6_000_000).await?;
+        let predicted_address = if self.config.token_base_uri.contains("{contract}") {
+            // The base URI is fixed in the constructor, so work out the address the contract
+            // will be deployed to, and pin the nonce so that's where it actually ends up.
+            let nonce = self
+                .rpc(
+                    self.web3
+                        .eth()
+                        .transaction_count(self.account, Some(BlockNumber::Pending)),
+                )
+                .await?;
+            options.nonce = Some(nonce);
+            create_address(self.account, nonce)
+        } else {
+            Address::zero()
+        };
+        let base_uri = expand_base_uri(
+            &self.config.token_base_uri,
+            predicted_address,
+            self.network_id,
+        );
         let contract_args = (
             // see NftPtrToken.sol's constructor
             /*name*/
-            format!(
-                "NftPtrToken {} {}",
-                Path::new(&std::env::args().next().unwrap())
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy(),
-                SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis()
-            ),
+            self.token_contract_name(),
             /*symbol*/
             "NFT".to_owned(),
             /*baseTokenURI*/
-            TOKEN_BASE_URI.to_owned(),
+            base_uri,
         );
-        let contract = if self.account_private_key.is_none() {
-            contract_builder
-                .execute(bytecode, contract_args, my_account)
-                .await
-        } else {
-            contract_builder
-                .sign_with_key_and_execute(
-                    bytecode,
-                    contract_args,
-                    web3::signing::SecretKeyRef::new(&self.account_private_key.unwrap()),
-                    Some(self.web3.eth().chain_id().await.unwrap().as_u64()),
-                )
-                .await
+        let (contract, receipt) = self
+            .deploy_contract(&self.token_artifact, contract_args, options, None)
+            .await?;
+        if !self.config.dry_run {
+            self.check_code_deployed(contract.address()).await?;
         }
-        .unwrap();
-        self.token_contract = Some(contract);
+        self.record_transaction(
+            &receipt,
+            TxKind::DeployToken {
+                contract: contract.address(),
+            },
+        );
+        self.token_contracts.push(contract);
+        self.token_deploy_receipts.push(receipt);
+        Ok(())
     }
 
-    fn mem_address_to_owner_contract_address(&self, a: u64) -> Address {
-        if self.instance_to_contract.contains_key(&a) {
-            return self.instance_to_contract[&a].address();
+    // Makes sure there's code at a contract we just deployed, so a deploy that didn't take
+    // fails initialize instead of every later mintOrMove. With load-balanced RPC endpoints the
+    // node we ask may be a block behind the one that gave us the receipt, so retry a little.
+    async fn check_code_deployed(&self, address: Address) -> Result<(), NftPtrError> {
+        for attempt in 0..CODE_CHECK_ATTEMPTS {
+            if attempt > 0 {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            if !self
+                .rpc(self.web3.eth().code(address, None))
+                .await?
+                .0
+                .is_empty()
+            {
+                return Ok(());
+            }
         }
-        self.account
+        Err(NftPtrError::ContractDeploy(format!(
+            "no code at {} after deploying",
+            checksum(&address)
+        )))
     }
 
-    pub async fn move_token(
-        &mut self,
-        owner_address: u64,
-        previous_owner_address: u64,
-        value: u64,
-        caller_pc: u64,
-        object_type: &str,
-    ) {
-        let caller_pc_lineinfo = string_for_pc_addr(caller_pc);
-        let caller_pc_backtrace_str = format!("{:x} {}", owner_address, caller_pc_lineinfo,);
-        let object_type_demangled = demangle_cpp(object_type);
-        let token_uri = format!("{:x} {}", value, object_type_demangled);
-        let token_uri_encoded =
-            percent_encoding::utf8_percent_encode(&token_uri, percent_encoding::NON_ALPHANUMERIC)
-                .to_string();
-        let owner_contract = self.mem_address_to_owner_contract_address(owner_address);
-        let previous_owner_contract =
-            self.mem_address_to_owner_contract_address(previous_owner_address);
-        // TODO(zhuowei): figure out what to do with the caller_pc
-        info!(
-            "Transferring {:#x} ({}) to {:#x} ({:#x}) from {:#x} ({:#x}) at PC={:#x} ({})",
-            value,
-            object_type_demangled,
-            owner_address,
-            owner_contract,
-            previous_owner_address,
-            previous_owner_contract,
-            caller_pc,
-            caller_pc_lineinfo,
-        );
-        let contract = self.token_contract.as_ref().unwrap();
-        let transaction_method = "mintOrMove";
-        let transaction_args = (
-            owner_contract,
-            previous_owner_contract,
-            U256::from(value),
-            token_uri_encoded,
-            caller_pc_backtrace_str,
-        );
-        let transaction_options = web3::contract::Options::with(|opt| {
-            if self.use_hardcoded_gas {
-                opt.gas = Some(220_000.into());
-            }
-        });
-        let transaction = if self.account_private_key.is_none() {
-            contract
-                .call_with_confirmations(
-                    transaction_method,
-                    transaction_args,
-                    self.account,
-                    transaction_options,
-                    self.num_confirmations,
-                )
-                .await
-        } else {
-            contract
-                .signed_call_with_confirmations(
-                    transaction_method,
-                    transaction_args,
-                    transaction_options,
-                    self.num_confirmations,
-                    web3::signing::SecretKeyRef::new(&self.account_private_key.unwrap()),
-                )
-                .await
+    fn token_contract_name(&self) -> String {
+        if let Some(token_name) = &self.config.token_name {
+            return token_name.clone();
         }
-        .unwrap();
-        info!("Transaction: {:#x}", transaction.transaction_hash);
-        if self.is_goerli() {
-            info!(
-                "https://testnets.opensea.io/assets/goerli/{:#x}/{:#x}",
-                self.token_contract.as_ref().unwrap().address(),
-                value
+        if self.config.deterministic_name {
+            return "NftPtrToken".to_string();
+        }
+        let program_name = match &self.config.program_name {
+            Some(program_name) => program_name.clone(),
+            None => Path::new(&std::env::args().next().unwrap())
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+        };
+        format!("NftPtrToken {} {}", program_name, (self.config.clock)())
+    }
+
+    // Deploys a contract and waits for its receipt, so a reverted deploy fails here
+    // instead of showing up later as calls to an address with no code.
+    async fn deploy_contract<P: Tokenize>(
+        &self,
+        artifact: &Artifact,
+        contract_args: P,
+        options: Options,
+        operation: Option<&TxOperation>,
+    ) -> Result<(Contract<T>, TransactionReceipt), NftPtrError> {
+        let abi = artifact.abi.clone();
+        let code = artifact.code.clone();
+        let contract_tokens = contract_args.into_tokens();
+        if self.config.dry_run {
+            info!("[dry run] Constructor args: {:?}", contract_tokens);
+        }
+        let data = match abi.constructor() {
+            Some(constructor) => constructor.encode_input(code, &contract_tokens)?,
+            None => code,
+        };
+        let receipt = self
+            .send_transaction(
+                None,
+                Bytes(data),
+                options,
+                self.deploy_confirmation_policy(),
+                operation,
             )
+            .await?;
+        self.stats.lock().unwrap().deploys += 1;
+        if receipt.status != Some(U64::from(1)) {
+            return Err(NftPtrError::DeployReverted {
+                transaction_hash: receipt.transaction_hash,
+                gas_used: receipt.gas_used,
+            });
         }
+        let address = receipt.contract_address.ok_or_else(|| {
+            NftPtrError::ContractDeploy(format!(
+                "no contract address in receipt for {:#x}",
+                receipt.transaction_hash
+            ))
+        })?;
+        Ok((Contract::new(self.web3.eth(), address, abi), receipt))
     }
-    pub async fn ptr_initialize(
-        &mut self,
-        owner_address: u64,
-        caller_pc: u64,
-        ptr_object_type: &str,
-    ) {
-        // rust-web3/examples/contract.rs
-        // TODO(zhuowei): understand this
-        let name = format!(
-            "{:x} {} {}",
-            owner_address,
-            demangle_cpp(ptr_object_type),
-            string_for_pc_addr(caller_pc),
-        );
-        info!("Deploying contract for nft_ptr {}", name);
-        let my_account = self.account;
-        let bytecode = include_str!("../../../contracts/out/NftPtrOwner.code");
-        let contract_builder = Contract::deploy(
-            self.web3.eth(),
-            include_bytes!("../../../contracts/out/NftPtrOwner.json"),
-        )
-        .unwrap()
-        .confirmations(self.num_confirmations)
-        .options(web3::contract::Options::with(|opt| {
-            // TODO(zhuowei): why does leaving this uncommented give me
-            // "VM Exception while processing transaction: revert"
-            //opt.value = Some(5.into());
-            //opt.gas_price = Some(5.into());
-            if self.use_hardcoded_gas {
-                opt.gas = Some(720_000.into());
+
+    async fn send_transaction(
+        &self,
+        to: Option<Address>,
+        data: Bytes,
+        options: Options,
+        policy: ConfirmationPolicy,
+        operation: Option<&TxOperation>,
+    ) -> Result<TransactionReceipt, NftPtrError> {
+        if self.config.dry_run {
+            return Ok(self.dry_run_transaction(to, data, options));
+        }
+        let transaction_hash = self
+            .submit_transaction(to, data, options, operation)
+            .await?;
+        let receipt = self
+            .wait_for_receipt(transaction_hash, policy, operation)
+            .await?;
+        if let Some(gas_used) = receipt.gas_used {
+            self.stats.lock().unwrap().gas_used += gas_used;
+        }
+        Ok(receipt)
+    }
+
+    // Hands a transaction to the node without waiting for it to be mined.
+    async fn submit_transaction(
+        &self,
+        to: Option<Address>,
+        data: Bytes,
+        options: Options,
+        operation: Option<&TxOperation>,
+    ) -> Result<H256, NftPtrError> {
+        // Held from picking the nonce until the node has accepted the transaction, so
+        // concurrent sends get consecutive nonces. Waiting for the receipt happens after
+        // it's released.
+        let mut next_nonce = self.next_nonce.lock().await;
+        let mut nonce = match options.nonce.or(*next_nonce) {
+            Some(nonce) => nonce,
+            None => self.pending_nonce().await?,
+        };
+        let explicit_nonce = options.nonce.is_some();
+        let mut sent = self
+            .send_with_nonce(to, data.clone(), options.clone(), nonce)
+            .await;
+        if self.config.resync_nonce_on_error
+            && !explicit_nonce
+            && matches!(&sent, Err(err) if err.is_nonce_error())
+        {
+            // Our count is off, so every later send would fail the same way: catch up with
+            // the node and try once more.
+            let node_nonce = self.pending_nonce().await?;
+            warn!(
+                "Nonce {} was refused, resyncing to {} from the node",
+                nonce, node_nonce
+            );
+            nonce = node_nonce;
+            sent = self.send_with_nonce(to, data, options, nonce).await;
+        }
+        // After a failed send we can't tell whether the nonce was used, so ask the node again.
+        *next_nonce = sent.as_ref().ok().map(|_| nonce + 1);
+        drop(next_nonce);
+        let transaction_hash = match sent {
+            Ok(transaction_hash) => transaction_hash,
+            Err(err) => {
+                self.notify_lifecycle(operation, || TxStage::Failed {
+                    transaction_hash: None,
+                    error: err.to_string(),
+                });
+                return Err(err);
             }
-        }));
+        };
+        self.submitted_transactions
+            .lock()
+            .unwrap()
+            .push(transaction_hash);
+        self.notify_lifecycle(operation, || TxStage::Submitted { transaction_hash });
+        Ok(transaction_hash)
+    }
 
-        let contract_args = (
-            // see NftPtrOwner.sol's constructor
-            /*name*/
-            name.to_owned(),
-        );
+    // The nonce the node expects next from our account, counting its pending transactions.
+    async fn pending_nonce(&self) -> Result<U256, NftPtrError> {
+        self.rpc(
+            self.web3
+                .eth()
+                .transaction_count(self.account, Some(BlockNumber::Pending)),
+        )
+        .await
+    }
 
-        let contract = if self.account_private_key.is_none() {
-            contract_builder
-                .execute(bytecode, contract_args, my_account)
-                .await
+    // Replaces our local nonce count with the node's, e.g. after something else sent a
+    // transaction from our account. Waits for any send in progress. Returns the nonce the
+    // next transaction will use.
+    #[instrument(skip(self))]
+    pub async fn resync_nonce(&self) -> Result<U256, NftPtrError> {
+        let mut next_nonce = self.next_nonce.lock().await;
+        let nonce = self.pending_nonce().await?;
+        *next_nonce = Some(nonce);
+        info!("Next nonce is {}", nonce);
+        Ok(nonce)
+    }
+
+    async fn send_with_nonce(
+        &self,
+        to: Option<Address>,
+        data: Bytes,
+        options: Options,
+        nonce: U256,
+    ) -> Result<H256, NftPtrError> {
+        if let Some(key) = self.signing_key() {
+            // From initialize: without one, sign_transaction would ask the node for it on
+            // every call.
+            let mut tx = TransactionParameters {
+                nonce: Some(nonce),
+                to,
+                gas_price: options.gas_price,
+                data,
+                chain_id: self.chain_id,
+                ..Default::default()
+            };
+            if let Some(gas) = options.gas {
+                tx.gas = gas;
+            }
+            if let Some(value) = options.value {
+                tx.value = value;
+            }
+            let signed = self
+                .rpc(self.web3.accounts().sign_transaction(tx, key))
+                .await?;
+            self.rpc(async {
+                self.web3
+                    .eth()
+                    .send_raw_transaction(signed.raw_transaction)
+                    .await
+                    .map_err(NftPtrError::from_send_error)
+            })
+            .await
         } else {
-            contract_builder
-                .sign_with_key_and_execute(
-                    bytecode,
-                    contract_args,
-                    web3::signing::SecretKeyRef::new(&self.account_private_key.unwrap()),
-                    Some(self.web3.eth().chain_id().await.unwrap().as_u64()),
+            let options = if self.config.replay_protected {
+                self.explicit_options(to, &data, options).await?
+            } else {
+                options
+            };
+            let tx = TransactionRequest {
+                from: self.account,
+                to,
+                gas: options.gas,
+                gas_price: options.gas_price,
+                value: options.value,
+                nonce: Some(nonce),
+                data: Some(data),
+                ..Default::default()
+            };
+            if self.config.access_lists && to.is_some() {
+                if let Some(access_list) = self.create_access_list(&tx).await {
+                    return self.send_with_access_list(&tx, access_list).await;
+                }
+            }
+            self.rpc(async {
+                self.web3
+                    .eth()
+                    .send_transaction(tx)
+                    .await
+                    .map_err(NftPtrError::from_send_error)
+            })
+            .await
+        }
+    }
+
+    // Asks the node which addresses and storage slots `tx` touches. None if it can't say
+    // (e.g. it has no eth_createAccessList), so the transaction goes without.
+    async fn create_access_list(&self, tx: &TransactionRequest) -> Option<serde_json::Value> {
+        let created = self
+            .rpc(web3::Transport::execute(
+                self.web3.transport(),
+                "eth_createAccessList",
+                vec![
+                    serde_json::to_value(tx).unwrap(),
+                    serde_json::json!("pending"),
+                ],
+            ))
+            .await;
+        match created {
+            Ok(created) => created.get("accessList").cloned(),
+            Err(err) => {
+                debug!("No access list, sending without one: {}", err);
+                None
+            }
+        }
+    }
+
+    // eth_sendTransaction for `tx` as an EIP-2930 transaction. The access list is added to
+    // the request's JSON as is.
+    async fn send_with_access_list(
+        &self,
+        tx: &TransactionRequest,
+        access_list: serde_json::Value,
+    ) -> Result<H256, NftPtrError> {
+        let mut request = serde_json::to_value(tx).unwrap();
+        request["type"] = serde_json::json!("0x1");
+        request["accessList"] = access_list;
+        let transaction_hash = self
+            .rpc(async {
+                web3::Transport::execute(
+                    self.web3.transport(),
+                    "eth_sendTransaction",
+                    vec![request],
                 )
                 .await
+                .map_err(NftPtrError::from_send_error)
+            })
+            .await?;
+        serde_json::from_value(transaction_hash)
+            .map_err(|err| web3::Error::Decoder(format!("eth_sendTransaction: {}", err)).into())
+    }
+
+    // Fills in the gas price and limit the node would otherwise pick when it signs, so
+    // what we send doesn't depend on its defaults.
+    async fn explicit_options(
+        &self,
+        to: Option<Address>,
+        data: &Bytes,
+        mut options: Options,
+    ) -> Result<Options, NftPtrError> {
+        if options.gas_price.is_none() {
+            options.gas_price = Some(self.rpc(self.web3.eth().gas_price()).await?);
         }
-        .unwrap();
+        if options.gas.is_none() {
+            let call = CallRequest {
+                from: Some(self.account),
+                to,
+                gas_price: options.gas_price,
+                value: options.value,
+                data: Some(data.clone()),
+                ..Default::default()
+            };
+            options.gas = Some(
+                self.rpc(async {
+                    self.web3
+                        .eth()
+                        .estimate_gas(call, None)
+                        .await
+                        .map_err(NftPtrError::from_send_error)
+                })
+                .await?,
+            );
+        }
+        Ok(options)
+    }
+
+    // Logs the transaction instead of sending it, and makes up a successful receipt for it.
+    fn dry_run_transaction(
+        &self,
+        to: Option<Address>,
+        data: Bytes,
+        options: Options,
+    ) -> TransactionReceipt {
         info!(
-            "Deployed contract for nft_ptr {} at {:#x}",
-            name,
-            contract.address()
+            "[dry run] Transaction from {} to {} gas {:?} data 0x{}",
+            checksum(&self.account),
+            to.map_or_else(|| "(deploy)".to_string(), |to| checksum(&to)),
+            options.gas,
+            hex::encode(&data.0)
         );
-        if self.is_goerli() {
-            info!(
-                "https://goerli.etherscan.io/token/{:#x}",
-                contract.address()
-            );
+        let transaction_hash = H256::from(web3::signing::keccak256(&data.0));
+        let contract_address = if to.is_none() {
+            let nonce = self.dry_run_nonce.fetch_add(1, Ordering::Relaxed) + 1;
+            Some(Address::from_low_u64_be(nonce))
+        } else {
+            None
+        };
+        serde_json::from_value(serde_json::json!({
+            "transactionHash": transaction_hash,
+            "transactionIndex": "0x0",
+            "blockHash": H256::zero(),
+            "blockNumber": "0x0",
+            "from": self.account,
+            "to": to,
+            "cumulativeGasUsed": "0x0",
+            "gasUsed": "0x0",
+            "contractAddress": contract_address,
+            "logs": [],
+            "status": "0x1",
+            "logsBloom": web3::types::H2048::zero(),
+        }))
+        .unwrap()
+    }
+
+    // A transaction was mined but reverted; replay it with eth_call against the state it
+    // ran on, which gets the node to tell us the revert reason.
+    async fn replay_revert_reason(
+        &self,
+        to: Address,
+        data: Bytes,
+        mined_block: Option<U64>,
+    ) -> Option<String> {
+        let call = CallRequest {
+            from: Some(self.account),
+            to: Some(to),
+            data: Some(data),
+            ..Default::default()
+        };
+        let block = mined_block
+            .map(|block| BlockId::Number(BlockNumber::Number(block.saturating_sub(U64::one()))));
+        match self.rpc(self.web3.eth().call(call, block)).await {
+            Ok(output) => error::decode_revert_reason(&output.0),
+            Err(NftPtrError::Web3(err)) => match NftPtrError::from_send_error(err) {
+                NftPtrError::Reverted { reason } => reason,
+                _ => None,
+            },
+            Err(_) => None,
         }
-        self.instance_to_contract.insert(owner_address, contract);
     }
 
-    pub async fn ptr_destroy(&mut self, owner_address: u64) {
-        // Don't actually destroy the contract so we can inspect later
-        // TODO(zhuowei): actually destroy this pointer?
-        self.instance_to_contract.remove(&owner_address);
+    // Options for a transaction whose hardcoded gas limit is `default_gas`: the gas price
+    // from the gas strategy, and the gas limit unless use_hardcoded_gas is off. Every
+    // transaction we build starts from this, so gas settings apply to all of them alike;
+    // the nonce is filled in when it's sent.
+    async fn tx_options(&self, default_gas: u64) -> Result<Options, NftPtrError> {
+        let gas_price = self.gas_price().await?;
+        Ok(Options::with(|opt| {
+            opt.gas_price = gas_price;
+            if self.config.use_hardcoded_gas {
+                opt.gas = Some(self.hardcoded_gas(default_gas));
+            }
+        }))
+    }
+
+    // A hardcoded gas limit scaled by config.gas_buffer_multiplier.
+    fn hardcoded_gas(&self, gas: u64) -> U256 {
+        scale_gas_limit(gas, self.config.gas_buffer_multiplier)
     }
-    fn is_goerli(&self) -> bool {
-        self.network_id == 5
+
+    // The gas price to send with a transaction, scaled by config.gas_price_multiplier,
+    // or None to let the node pick.
+    async fn gas_price(&self) -> Result<Option<U256>, NftPtrError> {
+        let gas_price = match &self.config.gas_strategy {
+            GasStrategy::NodeSuggested if self.config.gas_price_multiplier == 1.0 => {
+                return Ok(None)
+            }
+            GasStrategy::NodeSuggested => self.rpc(self.web3.eth().gas_price()).await?,
+            GasStrategy::Fixed(gas_price) => *gas_price,
+            GasStrategy::Oracle(oracle) => {
+                let oracle = oracle();
+                self.rpc(async { Ok::<_, NftPtrError>(oracle.await) })
+                    .await?
+            }
+        };
+        Ok(Some(scale_gas_price(
+            gas_price,
+            self.config.gas_price_multiplier,
+        )))
     }
-}
 
-pub async fn make_nft_ptr_lib_ipc() -> NftPtrLib<web3::transports::Ipc> {
-    // TODO(zhuowei): don't hardcode this
-    let transport = web3::transports::Ipc::new("TODOTODO").await.unwrap();
-    NftPtrLib::new(transport)
-}
+    // Awaits an RPC request, giving up after config.rpc_timeout so a stalled node can't hang
+    // the instrumented program forever.
+    async fn rpc<R, E: Into<NftPtrError>>(
+        &self,
+        request: impl Future<Output = Result<R, E>>,
+    ) -> Result<R, NftPtrError> {
+        match tokio::time::timeout(self.config.rpc_timeout, request).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(NftPtrError::Timeout(self.config.rpc_timeout)),
+        }
+    }
 
-pub fn make_nft_ptr_lib_localhost() -> NftPtrLib<web3::transports::Http> {
-    let transport = web3::transports::Http::new("http://127.0.0.1:7545").unwrap();
-    NftPtrLib::new(transport)
-}
+    // The key we sign transactions with locally, or None if the node signs them for us.
+    fn signing_key(&self) -> Option<SecretKeyRef<'_>> {
+        self.account_private_key.as_ref().map(SecretKeyRef::new)
+    }
 
-pub type NftPtrLibTransport =
-    web3::transports::Either<web3::transports::Http, web3::transports::Ipc>;
+    // Polls until the transaction is mined and confirmed according to `policy`.
+    // Gives up with NftPtrError::Timeout after config.request_timeout, if set: each poll is
+    // bounded by rpc_timeout, but a transaction that's never mined would keep us polling.
+    async fn wait_for_receipt(
+        &self,
+        transaction_hash: H256,
+        policy: ConfirmationPolicy,
+        operation: Option<&TxOperation>,
+    ) -> Result<TransactionReceipt, NftPtrError> {
+        let polled = self.poll_for_receipt(transaction_hash, policy, operation);
+        let result = match self.config.request_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, polled)
+                .await
+                .unwrap_or(Err(NftPtrError::Timeout(timeout))),
+            None => polled.await,
+        };
+        if let Ok(receipt) = &result {
+            self.forget_submitted(transaction_hash, receipt.block_number);
+        }
+        self.notify_lifecycle(operation, || match &result {
+            Ok(receipt) if receipt.status == Some(U64::zero()) => TxStage::Failed {
+                transaction_hash: Some(transaction_hash),
+                error: "transaction reverted".to_string(),
+            },
+            Ok(receipt) => TxStage::Confirmed {
+                transaction_hash,
+                gas_used: receipt.gas_used,
+            },
+            Err(err) => TxStage::Failed {
+                transaction_hash: Some(transaction_hash),
+                error: err.to_string(),
+            },
+        });
+        result
+    }
 
-pub async fn make_nft_ptr_lib() -> NftPtrLib<NftPtrLibTransport> {
-    let ipc_path = std::env::var("NFT_PTR_IPC");
-    let transport = if ipc_path.is_ok() {
-        NftPtrLibTransport::Right(web3::transports::Ipc::new(ipc_path.unwrap()).await.unwrap())
-    } else {
-        NftPtrLibTransport::Left(
-            web3::transports::Http::new(
-                &std::env::var("NFT_PTR_HTTP")
-                    .unwrap_or_else(|_| "http://127.0.0.1:7545".to_string()),
-            )
-            .unwrap(),
-        )
-    };
-    NftPtrLib::new(transport)
-}
+    // `transaction_hash` was mined in `mined_block`, so barrier only needs to wait for the
+    // block now, not for the transaction.
+    fn forget_submitted(&self, transaction_hash: H256, mined_block: Option<U64>) {
+        self.submitted_transactions
+            .lock()
+            .unwrap()
+            .retain(|submitted| *submitted != transaction_hash);
+        if let Some(mined_block) = mined_block {
+            let mut latest_mined_block = self.latest_mined_block.lock().unwrap();
+            *latest_mined_block = std::cmp::max(*latest_mined_block, Some(mined_block));
+        }
+    }
 
-fn string_for_pc_addr(pc_addr: u64) -> String {
-    let mut outstr: Option<String> = None;
-    let mut once: bool = false;
-    backtrace::resolve(pc_addr as _, |symbol| {
-        if once || symbol.name().is_none() {
-            return;
+    // Polls until `mined_block` is `depth` blocks deep, giving up after config.request_timeout
+    // like wait_for_receipt.
+    async fn wait_for_depth(&self, mined_block: U64, depth: usize) -> Result<(), NftPtrError> {
+        let polled = async {
+            while !self
+                .is_confirmed(mined_block, ConfirmationPolicy::Blocks(depth))
+                .await?
+            {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            Ok(())
+        };
+        match self.config.request_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, polled)
+                .await
+                .unwrap_or(Err(NftPtrError::Timeout(timeout))),
+            None => polled.await,
         }
-        once = true;
-        if symbol.filename().is_some() && symbol.lineno().is_some() {
-            let s = format!(
-                "{} ({}:{})",
-                demangle_cpp(symbol.name().unwrap().as_str().unwrap()),
-                symbol
-                    .filename()
-                    .unwrap()
-                    .file_name()
-                    .unwrap()
-                    .to_string_lossy(),
-                symbol.lineno().unwrap()
-            );
-            outstr = Some(s);
-        } else {
-            outstr = Some(demangle_cpp(symbol.name().unwrap().as_str().unwrap()));
+    }
+
+    async fn poll_for_receipt(
+        &self,
+        transaction_hash: H256,
+        policy: ConfirmationPolicy,
+        operation: Option<&TxOperation>,
+    ) -> Result<TransactionReceipt, NftPtrError> {
+        let mut reported_mined = false;
+        loop {
+            if let Some(receipt) = self
+                .rpc(self.web3.eth().transaction_receipt(transaction_hash))
+                .await?
+            {
+                if let Some(mined_block) = receipt.block_number {
+                    if !reported_mined {
+                        reported_mined = true;
+                        self.notify_lifecycle(operation, || TxStage::Mined {
+                            transaction_hash,
+                            block_number: mined_block.as_u64(),
+                        });
+                    }
+                    if self.is_confirmed(mined_block, policy).await? {
+                        return Ok(receipt);
+                    }
+                }
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
         }
-    });
-    if !once {
-        return format!("{:x}", pc_addr);
     }
-    outstr.unwrap()
-}
 
-fn demangle_cpp(typename: &str) -> String {
-    // I could just call abi::__cxx_demangle in the C++, but lol WRITE IT IN RUST
-    let demangled = cpp_demangle::Symbol::new(typename);
-    if let Ok(demangled_out) = demangled {
-        return demangled_out.to_string();
+    async fn is_confirmed(
+        &self,
+        mined_block: U64,
+        policy: ConfirmationPolicy,
+    ) -> Result<bool, NftPtrError> {
+        match policy {
+            ConfirmationPolicy::None => Ok(true),
+            ConfirmationPolicy::Blocks(confirmations) => {
+                let latest_block = self.rpc(self.web3.eth().block_number()).await?;
+                Ok(latest_block.as_u64() >= mined_block.as_u64() + confirmations as u64)
+            }
+            ConfirmationPolicy::UntilTimestamp(delay) => {
+                let mined = self
+                    .rpc(
+                        self.web3
+                            .eth()
+                            .block(BlockId::Number(BlockNumber::Number(mined_block))),
+                    )
+                    .await?;
+                let latest = self
+                    .rpc(self.web3.eth().block(BlockId::Number(BlockNumber::Latest)))
+                    .await?;
+                Ok(match (mined, latest) {
+                    (Some(mined), Some(latest)) => {
+                        latest.timestamp >= mined.timestamp + U256::from(delay.as_secs())
+                    }
+                    _ => false,
+                })
+            }
+        }
     }
-    typename.to_string()
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn mem_address_to_owner_contract_address(&self, a: u64) -> Result<Address, NftPtrError> {
+        self.owner_contract_of(a).map(|owner| owner.address())
     }
-    #[test]
-    fn demangle_cpp_example() {
-        assert_eq!(demangle_cpp("P3Cow"), "Cow*");
+
+    // The lookup move_token does: tells the caller whether `addr` is an nft_ptr we're
+    // tracking, or where its tokens go instead. Unknown owners are handled according to
+    // config.owner_fallback, so this fails for them with OwnerFallback::ErrorOnUnknown.
+    pub fn owner_contract_of(&self, addr: u64) -> Result<OwnerContract, NftPtrError> {
+        match self.resolve_owner(addr) {
+            OwnerResolution::Tracked(address) => Ok(OwnerContract::Tracked(address)),
+            OwnerResolution::DefaultAccount(address) => Ok(OwnerContract::Fallback(address)),
+            OwnerResolution::Unknown => {
+                let fallback = match self.config.owner_fallback {
+                    OwnerFallback::FallbackToAccount => self.account,
+                    OwnerFallback::FallbackToZero => Address::zero(),
+                    OwnerFallback::ErrorOnUnknown => {
+                        return Err(NftPtrError::UntrackedPointer(addr))
+                    }
+                };
+                debug!(
+                    "{:#x} isn't a tracked nft_ptr, falling back to {}",
+                    addr,
+                    checksum(&fallback)
+                );
+                Ok(OwnerContract::Fallback(fallback))
+            }
+        }
+    }
+
+    // The lookup move_token does, without the fallback: tells the caller whether `mem_addr`
+    // is an nft_ptr we're tracking (Tracked), the null owner (DefaultAccount), or something
+    // we've never heard of (Unknown).
+    pub fn resolve_owner(&self, mem_addr: u64) -> OwnerResolution {
+        if let Some(contracts) = self.instance_to_contract.read().unwrap().get(&mem_addr) {
+            return OwnerResolution::Tracked(contracts.last().unwrap().address());
+        }
+        if mem_addr == 0 {
+            OwnerResolution::DefaultAccount(self.account)
+        } else {
+            OwnerResolution::Unknown
+        }
+    }
+
+    // encode_token_uri, with our run id (if any) first in the metadata. If that's longer than
+    // config.max_token_uri_len, a hash of it goes in the token instead, and the full URI
+    // comes back second.
+    fn token_uri(
+        &self,
+        value: u64,
+        object_type: &str,
+        metadata: &[(&str, &str)],
+    ) -> (String, Option<String>) {
+        let metadata: Vec<(&str, &str)> = self
+            .config
+            .run_id
+            .as_deref()
+            .map(|run_id| ("run", run_id))
+            .into_iter()
+            .chain(metadata.iter().copied())
+            .collect();
+        let token_uri = encode_token_uri(value, object_type, &metadata);
+        match self.config.max_token_uri_len {
+            Some(max_len) if token_uri.len() > max_len => {
+                (hashed_token_uri(value, &token_uri), Some(token_uri))
+            }
+            _ => (token_uri, None),
+        }
+    }
+
+    // caller_location and its string for `caller_pc`, resolved once per PC.
+    fn resolve_caller(&self, caller_pc: u64) -> (CallerLocation, String) {
+        if let Some(resolved) = self.caller_locations.lock().unwrap().get(&caller_pc) {
+            return resolved.clone();
+        }
+        // Not holding the lock while symbolizing, so other moves can still use the cache.
+        let location = self.locate_pc(caller_pc);
+        let resolved = (location.clone(), location.to_string());
+        self.caller_locations
+            .lock()
+            .unwrap()
+            .insert(caller_pc, resolved.clone());
+        resolved
+    }
+
+    // Resolves `pc` with config.symbolizer: against the object file parsed when the lib was
+    // built, or in this process.
+    fn locate_pc(&self, pc: u64) -> CallerLocation {
+        match &self.object_context {
+            Some(context) => context
+                .lock()
+                .unwrap()
+                .resolve(pc, self.config.demangle_mode),
+            None => caller_location(pc, self.config.demangle_mode),
+        }
+    }
+
+    // Whether ptr_initialize has deployed an owner contract for `owner_address` that
+    // ptr_destroy hasn't forgotten yet.
+    pub fn is_tracked(&self, owner_address: u64) -> bool {
+        self.instance_to_contract
+            .read()
+            .unwrap()
+            .contains_key(&owner_address)
+    }
+
+    // Every tracked (nft_ptr address, owner contract) pair, sorted by address. An address
+    // initialized more than once is listed once per owner contract, oldest first.
+    pub fn owner_contracts(&self) -> Vec<(u64, Address)> {
+        let mut owner_contracts: Vec<(u64, Address)> = self
+            .instance_to_contract
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|(owner_address, contracts)| {
+                contracts
+                    .iter()
+                    .map(move |contract| (*owner_address, contract.address()))
+            })
+            .collect();
+        owner_contracts.sort_by_key(|(owner_address, _)| *owner_address);
+        owner_contracts
+    }
+
+    // Records that the object at `value` is now owned by the nft_ptr at `owner_address`,
+    // and returns the id of its token.
+    pub async fn move_token(
+        &self,
+        owner_address: u64,
+        previous_owner_address: u64,
+        value: u64,
+        caller_pc: u64,
+        object_type: &str,
+    ) -> Result<U256, NftPtrError> {
+        self.move_token_with_metadata(
+            owner_address,
+            previous_owner_address,
+            value,
+            caller_pc,
+            object_type,
+            &[],
+        )
+        .await
+    }
+
+    // move_token into the nft_ptr `owner` from ptr_initialize.
+    pub async fn move_token_to(
+        &self,
+        owner: &PtrToken,
+        previous_owner_address: u64,
+        value: u64,
+        caller_pc: u64,
+        object_type: &str,
+    ) -> Result<U256, NftPtrError> {
+        self.move_token(
+            owner.owner_address,
+            previous_owner_address,
+            value,
+            caller_pc,
+            object_type,
+        )
+        .await
+    }
+
+    // Like move_token, but also puts `metadata` (e.g. [("thread", "3"), ("size", "64")]) in
+    // the token URI's query string, for the metadata server to show.
+    pub async fn move_token_with_metadata(
+        &self,
+        owner_address: u64,
+        previous_owner_address: u64,
+        value: u64,
+        caller_pc: u64,
+        object_type: &str,
+        metadata: &[(&str, &str)],
+    ) -> Result<U256, NftPtrError> {
+        self.send_move(
+            owner_address,
+            previous_owner_address,
+            value,
+            caller_pc,
+            demangle_cpp(object_type, self.config.demangle_mode),
+            metadata,
+            None,
+        )
+        .await
+    }
+
+    // Like move_token, but with the caller's location already resolved (e.g. by a JIT that
+    // knows its own source positions): `location` is passed to mintOrMove as is, instead of
+    // symbolizing `caller_pc`.
+    pub async fn move_token_with_location(
+        &self,
+        owner_address: u64,
+        previous_owner_address: u64,
+        value: u64,
+        caller_pc: u64,
+        object_type: &str,
+        location: &str,
+    ) -> Result<U256, NftPtrError> {
+        self.send_move(
+            owner_address,
+            previous_owner_address,
+            value,
+            caller_pc,
+            demangle_cpp(object_type, self.config.demangle_mode),
+            &[],
+            Some(location),
+        )
+        .await
+    }
+
+    // Like move_token, but for an `object_type` that's already the name to show (e.g. one the
+    // caller demangled itself): it goes in the token URI as is, without demangle_cpp.
+    // move_token and move_token_with_metadata expect a mangled name (or typeid().name()).
+    pub async fn move_token_raw(
+        &self,
+        owner_address: u64,
+        previous_owner_address: u64,
+        value: u64,
+        caller_pc: u64,
+        object_type: &str,
+    ) -> Result<U256, NftPtrError> {
+        self.send_move(
+            owner_address,
+            previous_owner_address,
+            value,
+            caller_pc,
+            Cow::Borrowed(object_type),
+            &[],
+            None,
+        )
+        .await
+    }
+
+    #[instrument(
+        name = "move_token",
+        skip(self, previous_owner_address, object_type_demangled, metadata, location),
+        fields(previous_owner = previous_owner_address, object_type = &&*object_type_demangled)
+    )]
+    async fn send_move(
+        &self,
+        owner_address: u64,
+        previous_owner_address: u64,
+        value: u64,
+        caller_pc: u64,
+        object_type_demangled: Cow<'_, str>,
+        metadata: &[(&str, &str)],
+        // The caller's location, if it's already resolved.
+        location: Option<&str>,
+    ) -> Result<U256, NftPtrError> {
+        let token_id = token_id_for(value);
+        if self.is_paused() {
+            debug!(
+                "Paused, not moving {:#x} to {:#x} from {:#x}",
+                value, owner_address, previous_owner_address
+            );
+            return Ok(token_id);
+        }
+        let (caller, caller_pc_lineinfo) = match location {
+            Some(location) => (
+                CallerLocation {
+                    pc: caller_pc,
+                    symbol: Some(location.to_string()),
+                    file: None,
+                    line: None,
+                },
+                location.to_string(),
+            ),
+            None => self.resolve_caller(caller_pc),
+        };
+        let (token_uri_encoded, full_token_uri) =
+            self.token_uri(value, &object_type_demangled, metadata);
+        let owner_contract = self.mem_address_to_owner_contract_address(owner_address)?;
+        let previous_owner_contract =
+            self.mem_address_to_owner_contract_address(previous_owner_address)?;
+        detail!(
+            self,
+            owner_contract = ?owner_contract,
+            previous_owner_contract = ?previous_owner_contract,
+            "Transferring {:#x} ({}) to {:#x} ({}) from {:#x} ({}) at PC={:#x} ({})",
+            value,
+            object_type_demangled,
+            owner_address,
+            checksum(&owner_contract),
+            previous_owner_address,
+            checksum(&previous_owner_contract),
+            caller_pc,
+            caller_pc_lineinfo,
+        );
+        if self.config.dedup_moves
+            && self.last_moves.lock().unwrap().get(&value) == Some(&owner_contract)
+        {
+            debug!(
+                "Already moved {:#x} to {}, skipping duplicate move",
+                value,
+                checksum(&owner_contract)
+            );
+            return Ok(token_id);
+        }
+        if self.config.skip_redundant_moves
+            && !self.config.dry_run
+            && owner_contract == previous_owner_contract
+            && self.owner_of(value).await? == Some(owner_contract)
+        {
+            debug!(
+                "{:#x} is already owned by {}, skipping move",
+                value,
+                checksum(&owner_contract)
+            );
+            return Ok(token_id);
+        }
+        if let Some(full_token_uri) = full_token_uri {
+            // The metadata server can only get this from us.
+            info!(
+                token_uri = %full_token_uri,
+                "Token URI for {:#x} is too long, storing {} instead",
+                value,
+                token_uri_encoded
+            );
+            self.write_audit_record(&serde_json::json!({
+                "timestamp_millis": (self.config.clock)() as u64,
+                "kind": {
+                    "type": "HashedTokenUri",
+                    "value": value,
+                    "hashed_token_uri": token_uri_encoded,
+                    "token_uri": full_token_uri,
+                },
+            }));
+        }
+        let contract = self.token_contract_for(value)?;
+        let transaction_method = "mintOrMove";
+        let transaction_tokens = mint_or_move_args(
+            owner_address,
+            owner_contract,
+            previous_owner_contract,
+            value,
+            &caller_pc_lineinfo,
+            token_uri_encoded,
+        );
+        let transaction_options = self.tx_options(220_000).await?;
+        if self.config.dry_run {
+            info!(
+                "[dry run] {} args: {:?}",
+                transaction_method, transaction_tokens
+            );
+        }
+        let transaction_data = contract
+            .abi()
+            .function(transaction_method)?
+            .encode_input(&transaction_tokens)?;
+        let operation = TxOperation::MoveToken {
+            value,
+            owner_address,
+            previous_owner_address,
+        };
+        let kind = TxKind::MoveToken {
+            value,
+            owner_address,
+            previous_owner_address,
+            owner_contract,
+            previous_owner_contract,
+            object_type: object_type_demangled.to_string(),
+            caller,
+        };
+        if self.config.fire_and_forget && !self.config.dry_run {
+            let transaction_hash = self
+                .submit_transaction(
+                    Some(contract.address()),
+                    Bytes(transaction_data.clone()),
+                    transaction_options,
+                    Some(&operation),
+                )
+                .await?;
+            detail!(
+                self,
+                tx_hash = ?transaction_hash,
+                token_id = value,
+                "Transaction: {:#x} (not waiting for it)",
+                transaction_hash
+            );
+            if self.config.dedup_moves {
+                self.last_moves
+                    .lock()
+                    .unwrap()
+                    .insert(value, owner_contract);
+            }
+            self.pending_moves.lock().unwrap().push(PendingMove {
+                transaction_hash,
+                contract: contract.address(),
+                data: transaction_data,
+                kind,
+                operation,
+            });
+            return Ok(token_id);
+        }
+        let transaction = self
+            .send_transaction(
+                Some(contract.address()),
+                Bytes(transaction_data.clone()),
+                transaction_options,
+                self.call_confirmation_policy(),
+                Some(&operation),
+            )
+            .await?;
+        detail!(
+            self,
+            tx_hash = ?transaction.transaction_hash,
+            token_id = value,
+            "Transaction: {:#x}",
+            transaction.transaction_hash
+        );
+        self.finish_move(&transaction, contract.address(), transaction_data, kind)
+            .await?;
+        if self.config.dedup_moves {
+            self.last_moves
+                .lock()
+                .unwrap()
+                .insert(value, owner_contract);
+        }
+        if let Some(url) = self.opensea_asset_url(value) {
+            detail!(self, "{}", url);
+        }
+        Ok(token_id)
+    }
+    // Checks a mined mintOrMove succeeded, and records it.
+    async fn finish_move(
+        &self,
+        transaction: &TransactionReceipt,
+        contract: Address,
+        transaction_data: Vec<u8>,
+        kind: TxKind,
+    ) -> Result<(), NftPtrError> {
+        if transaction.status == Some(U64::zero()) {
+            let reason = self
+                .replay_revert_reason(contract, Bytes(transaction_data), transaction.block_number)
+                .await;
+            return Err(NftPtrError::Reverted { reason });
+        }
+        self.stats.lock().unwrap().moves += 1;
+        self.record_transaction(transaction, kind);
+        Ok(())
+    }
+
+    // Waits for the moves sent in fire_and_forget mode, then checks and records each one as
+    // move_token would have if it had waited. Every pending move is checked; the error is
+    // the first one's that failed.
+    #[instrument(skip(self))]
+    pub async fn flush(&self) -> Result<(), NftPtrError> {
+        let pending_moves = std::mem::take(&mut *self.pending_moves.lock().unwrap());
+        let mut result = Ok(());
+        for pending in pending_moves {
+            let finished = match self
+                .wait_for_receipt(
+                    pending.transaction_hash,
+                    self.call_confirmation_policy(),
+                    Some(&pending.operation),
+                )
+                .await
+            {
+                Ok(receipt) => {
+                    if let Some(gas_used) = receipt.gas_used {
+                        self.stats.lock().unwrap().gas_used += gas_used;
+                    }
+                    self.finish_move(&receipt, pending.contract, pending.data, pending.kind)
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = finished {
+                warn!("Move {:#x} failed: {}", pending.transaction_hash, err);
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+
+    // Roughly what a move_token of `value` would cost in wei (estimated gas times the gas price
+    // we'd pay), without sending anything. The estimate is for minting the token to our own
+    // account from caller PC 0, which is close enough for budgeting.
+    #[instrument(skip(self))]
+    pub async fn estimate_move_cost(
+        &self,
+        value: u64,
+        object_type: &str,
+    ) -> Result<U256, NftPtrError> {
+        let contract = self.token_contract_for(value)?;
+        let owner_contract = self.mem_address_to_owner_contract_address(0)?;
+        let tokens = mint_or_move_args(
+            0,
+            owner_contract,
+            owner_contract,
+            value,
+            &self.locate_pc(0).to_string(),
+            self.token_uri(
+                value,
+                &demangle_cpp(object_type, self.config.demangle_mode),
+                &[],
+            )
+            .0,
+        );
+        let data = contract
+            .abi()
+            .function("mintOrMove")?
+            .encode_input(&tokens)?;
+        let call = CallRequest {
+            from: Some(self.account),
+            to: Some(contract.address()),
+            data: Some(Bytes(data)),
+            ..Default::default()
+        };
+        let gas = self
+            .rpc(async {
+                self.web3
+                    .eth()
+                    .estimate_gas(call, None)
+                    .await
+                    .map_err(NftPtrError::from_send_error)
+            })
+            .await?;
+        let gas_price = match self.gas_price().await? {
+            Some(gas_price) => gas_price,
+            None => self.rpc(self.web3.eth().gas_price()).await?,
+        };
+        Ok(gas * gas_price)
+    }
+
+    // The contract (or account) that currently owns the token for `value`,
+    // or None if it hasn't been minted.
+    #[instrument(skip(self))]
+    pub async fn owner_of(&self, value: u64) -> Result<Option<Address>, NftPtrError> {
+        let contract = self.token_contract_for(value)?;
+        self.rpc(async {
+            let result: Result<Address, _> = contract
+                .query(
+                    "ownerOf",
+                    (token_id_for(value),),
+                    None,
+                    Options::default(),
+                    None,
+                )
+                .await;
+            match result {
+                Ok(owner) => Ok(Some(owner)),
+                // ownerOf reverts for tokens that don't exist.
+                Err(web3::contract::Error::Api(web3::Error::Rpc(_))) => Ok(None),
+                Err(err) => Err(err),
+            }
+        })
+        .await
+    }
+
+    // Lets `operator` (e.g. a marketplace) transfer every pointer token we hold, via ERC721's
+    // setApprovalForAll on the token contract. Returns the transaction hash; with several
+    // token shards, each gets its own transaction, and the last one's hash is returned.
+    #[instrument(skip(self))]
+    pub async fn set_approval_for_all(
+        &self,
+        operator: Address,
+        approved: bool,
+    ) -> Result<H256, NftPtrError> {
+        if self.token_contracts.is_empty() {
+            return Err(NftPtrError::NotInitialized);
+        }
+        let mut transaction_hash = H256::zero();
+        for contract in &self.token_contracts {
+            transaction_hash = self
+                .send_token_call(
+                    contract,
+                    "setApprovalForAll",
+                    (operator, approved).into_tokens(),
+                    TxKind::SetApprovalForAll { operator, approved },
+                )
+                .await?;
+        }
+        Ok(transaction_hash)
+    }
+
+    // Burns the token for `value`, e.g. to mark one allocation freed while its owner
+    // contract lives on for the others it holds. Returns the transaction hash.
+    #[instrument(skip(self))]
+    pub async fn burn_token(&self, value: u64) -> Result<H256, NftPtrError> {
+        let transaction_hash = self
+            .send_token_call(
+                self.token_contract_for(value)?,
+                "burn",
+                (token_id_for(value),).into_tokens(),
+                TxKind::BurnToken { value },
+            )
+            .await?;
+        self.last_moves.lock().unwrap().remove(&value);
+        detail!(self, tx_hash = ?transaction_hash, "Burned {:#x}", value);
+        Ok(transaction_hash)
+    }
+
+    // Calls `method` on a token or owner contract; mintOrMove has its own error handling.
+    async fn send_token_call(
+        &self,
+        contract: &Contract<T>,
+        method: &str,
+        tokens: Vec<ethabi::Token>,
+        kind: TxKind,
+    ) -> Result<H256, NftPtrError> {
+        let options = self.tx_options(100_000).await?;
+        let data = contract.abi().function(method)?.encode_input(&tokens)?;
+        let receipt = self
+            .send_transaction(
+                Some(contract.address()),
+                Bytes(data.clone()),
+                options,
+                self.call_confirmation_policy(),
+                None,
+            )
+            .await?;
+        if receipt.status == Some(U64::zero()) {
+            let reason = self
+                .replay_revert_reason(contract.address(), Bytes(data), receipt.block_number)
+                .await;
+            return Err(NftPtrError::Reverted { reason });
+        }
+        self.record_transaction(&receipt, kind);
+        Ok(receipt.transaction_hash)
+    }
+
+    // Unsticks the transaction at `nonce` (e.g. an underpriced move holding up every later
+    // nonce) by sending a zero-value transfer to ourselves at the same nonce, priced
+    // `bump_percent` above the original's gas price or the current one, whichever is higher.
+    // Nodes only accept a replacement that pays at least 10% more than the original. Only
+    // works with a local signing key, since otherwise the node picks the nonces.
+    #[instrument(skip(self))]
+    pub async fn replace_transaction(
+        &self,
+        nonce: U256,
+        bump_percent: u8,
+    ) -> Result<H256, NftPtrError> {
+        let key = self
+            .signing_key()
+            .ok_or(NftPtrError::LocalSigningRequired("replace_transaction"))?;
+        let current_gas_price = match self.gas_price().await? {
+            Some(gas_price) => gas_price,
+            None => self.rpc(self.web3.eth().gas_price()).await?,
+        };
+        let gas_price = match self.pending_gas_price(nonce).await? {
+            Some(original) => std::cmp::max(original, current_gas_price),
+            None => current_gas_price,
+        };
+        // A price this close to U256::MAX can't be paid anyway; the node will say so.
+        let gas_price = gas_price
+            .checked_mul(U256::from(100 + u64::from(bump_percent)))
+            .map_or(U256::max_value(), |gas_price| gas_price / 100);
+        let tx = TransactionParameters {
+            nonce: Some(nonce),
+            to: Some(self.account),
+            gas: U256::from(21_000),
+            gas_price: Some(gas_price),
+            chain_id: self.chain_id,
+            ..Default::default()
+        };
+        if self.config.dry_run {
+            info!(
+                "[dry run] Replacing transaction with nonce {}: {:?}",
+                nonce, tx
+            );
+            return Ok(H256::zero());
+        }
+        let signed = self
+            .rpc(self.web3.accounts().sign_transaction(tx, key))
+            .await?;
+        let transaction_hash = self
+            .rpc(async {
+                self.web3
+                    .eth()
+                    .send_raw_transaction(signed.raw_transaction)
+                    .await
+                    .map_err(NftPtrError::from_send_error)
+            })
+            .await?;
+        info!(
+            "Replaced transaction with nonce {} with {:#x}",
+            nonce, transaction_hash
+        );
+        Ok(transaction_hash)
+    }
+
+    // The gas price of the transaction we sent at `nonce`, if the node still has it pending.
+    async fn pending_gas_price(&self, nonce: U256) -> Result<Option<U256>, NftPtrError> {
+        let submitted = self.submitted_transactions.lock().unwrap().clone();
+        for transaction_hash in submitted {
+            let transaction = self
+                .rpc(
+                    self.web3
+                        .eth()
+                        .transaction(TransactionId::Hash(transaction_hash)),
+                )
+                .await?;
+            if let Some(transaction) = transaction {
+                if transaction.nonce == nonce && transaction.block_number.is_none() {
+                    return Ok(Some(transaction.gas_price));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Checks on a transaction sent earlier, e.g. a move sent with zero confirmations.
+    #[instrument(skip(self))]
+    pub async fn transaction_status(&self, hash: H256) -> Result<TxStatus, NftPtrError> {
+        let receipt = self.rpc(self.web3.eth().transaction_receipt(hash)).await?;
+        if let Some(TransactionReceipt {
+            block_number: Some(block),
+            status,
+            ..
+        }) = receipt
+        {
+            return Ok(TxStatus::Mined {
+                block,
+                success: status == Some(U64::one()),
+            });
+        }
+        let transaction = self
+            .rpc(self.web3.eth().transaction(TransactionId::Hash(hash)))
+            .await?;
+        Ok(match transaction {
+            Some(_) => TxStatus::Pending,
+            None => TxStatus::Dropped,
+        })
+    }
+
+    // Whether the token for `value` has been minted, i.e. whether move_token's mintOrMove
+    // will move it rather than mint it.
+    #[instrument(skip(self))]
+    pub async fn token_exists(&self, value: u64) -> Result<bool, NftPtrError> {
+        Ok(self.owner_of(value).await?.is_some())
+    }
+
+    // Every token currently owned by the nft_ptr at `owner_address`.
+    #[instrument(skip(self))]
+    pub async fn tokens_of_owner(&self, owner_address: u64) -> Result<Vec<U256>, NftPtrError> {
+        let owner = self.mem_address_to_owner_contract_address(owner_address)?;
+        if self.token_contracts.is_empty() {
+            return Err(NftPtrError::NotInitialized);
+        }
+        // Shards loaded with load_state needn't be from one deployment, so ask each of them.
+        let mut enumerable = true;
+        for contract in &self.token_contracts {
+            enumerable &= self.is_enumerable(contract).await?;
+        }
+        if enumerable {
+            let mut tokens = Vec::new();
+            for contract in &self.token_contracts {
+                let balance: U256 = self
+                    .rpc(contract.query("balanceOf", (owner,), None, Options::default(), None))
+                    .await?;
+                for index in 0..balance.as_u64() {
+                    let token: U256 = self
+                        .rpc(contract.query(
+                            "tokenOfOwnerByIndex",
+                            (owner, U256::from(index)),
+                            None,
+                            Options::default(),
+                            None,
+                        ))
+                        .await?;
+                    tokens.push(token);
+                }
+            }
+            return Ok(tokens);
+        }
+        // Not enumerable (e.g. a token contract loaded at runtime), so replay the
+        // Transfer events to work out who owns each token now.
+        let from_block = self
+            .token_deploy_receipt()
+            .and_then(|receipt| receipt.block_number)
+            .unwrap_or_else(U64::zero);
+        let mut token_owners = BTreeMap::new();
+        for transfer in self.fetch_transfer_history(from_block).await? {
+            token_owners.insert(transfer.token_id, transfer.to);
+        }
+        Ok(token_owners
+            .into_iter()
+            .filter(|(_, token_owner)| *token_owner == owner)
+            .map(|(token, _)| token)
+            .collect())
+    }
+
+    // Whether `contract` implements ERC721Enumerable, so its tokens can be listed directly.
+    async fn is_enumerable(&self, contract: &Contract<T>) -> Result<bool, NftPtrError> {
+        self.rpc(async {
+            let supported: Result<bool, _> = contract
+                .query(
+                    "supportsInterface",
+                    (ethabi::Token::FixedBytes(
+                        ERC721_ENUMERABLE_INTERFACE_ID.to_vec(),
+                    ),),
+                    None,
+                    Options::default(),
+                    None,
+                )
+                .await;
+            Ok::<_, NftPtrError>(supported.unwrap_or(false))
+        })
+        .await
+    }
+
+    // Every Transfer (including mints) of the token contracts since `from_block`, oldest first,
+    // for rebuilding the ownership timeline after the fact.
+    #[instrument(skip(self))]
+    pub async fn fetch_transfer_history(
+        &self,
+        from_block: U64,
+    ) -> Result<Vec<TransferRecord>, NftPtrError> {
+        let addresses: Vec<Address> = self
+            .token_contracts
+            .iter()
+            .map(|contract| contract.address())
+            .collect();
+        let latest_block = self.rpc(self.web3.eth().block_number()).await?;
+        let mut transfers = Vec::new();
+        let mut page_start = from_block;
+        // Providers cap how many blocks (or logs) one eth_getLogs may cover.
+        while page_start <= latest_block {
+            let page_end = std::cmp::min(page_start + U64::from(LOG_PAGE_BLOCKS - 1), latest_block);
+            let filter = FilterBuilder::default()
+                .address(addresses.clone())
+                .topics(Some(vec![transfer_event_topic()]), None, None, None)
+                .from_block(BlockNumber::Number(page_start))
+                .to_block(BlockNumber::Number(page_end))
+                .build();
+            for log in self.rpc(self.web3.eth().logs(filter)).await? {
+                if log.topics.len() != 4 {
+                    continue;
+                }
+                transfers.push(TransferRecord {
+                    from: Address::from_slice(&log.topics[1].as_bytes()[12..]),
+                    to: Address::from_slice(&log.topics[2].as_bytes()[12..]),
+                    token_id: U256::from_big_endian(log.topics[3].as_bytes()),
+                    block: log.block_number.unwrap_or(page_end),
+                    transaction_hash: log.transaction_hash.unwrap_or_else(H256::zero),
+                });
+            }
+            page_start = page_end + U64::one();
+        }
+        Ok(transfers)
+    }
+
+    #[instrument(
+        skip(self, ptr_object_type),
+        fields(object_type = tracing::field::Empty)
+    )]
+    pub async fn ptr_initialize(
+        &self,
+        owner_address: u64,
+        caller_pc: u64,
+        ptr_object_type: &str,
+    ) -> Result<PtrToken, NftPtrError> {
+        if self.is_paused() {
+            debug!(
+                "Paused, not deploying contract for nft_ptr {:x}",
+                owner_address
+            );
+            return Err(NftPtrError::Paused);
+        }
+        let ptr_object_type_demangled = demangle_cpp(ptr_object_type, self.config.demangle_mode);
+        tracing::Span::current().record("object_type", &&*ptr_object_type_demangled);
+        let mut name = format!(
+            "{:x} {} {}",
+            owner_address,
+            ptr_object_type_demangled,
+            self.resolve_caller(caller_pc).1,
+        );
+        if let Some(run_id) = &self.config.run_id {
+            name.push_str(&format!(" run {}", run_id));
+        }
+        detail!(self, "Deploying contract for nft_ptr {}", name);
+        let artifact = match self
+            .owner_templates
+            .iter()
+            .find(|template| ptr_object_type_demangled.contains(&template.pattern))
+        {
+            Some(template) => &template.artifact,
+            None => &self.owner_artifact,
+        };
+        // TODO(zhuowei): why does setting opt.value = Some(5.into()) give me
+        // "VM Exception while processing transaction: revert"
+        let options = self.tx_options(720_000).await?;
+
+        let contract_args = (
+            // see NftPtrOwner.sol's constructor
+            /*name*/
+            name.to_owned(),
+        );
+
+        let operation = TxOperation::PtrInitialize {
+            owner_address,
+            object_type: ptr_object_type_demangled.to_string(),
+        };
+        let (contract, receipt) = self
+            .deploy_contract(artifact, contract_args, options, Some(&operation))
+            .await?;
+        detail!(
+            self,
+            tx_hash = ?receipt.transaction_hash,
+            contract = ?contract.address(),
+            "Deployed contract for nft_ptr {} at {} (gas used: {})",
+            name,
+            checksum(&contract.address()),
+            format_gas_used(&receipt)
+        );
+        if let Some(url) = self.etherscan_token_url(contract.address()) {
+            detail!(self, "{}", url);
+        }
+        self.record_transaction(
+            &receipt,
+            TxKind::PtrInitialize {
+                owner_address,
+                contract: contract.address(),
+                object_type: ptr_object_type_demangled.to_string(),
+            },
+        );
+        let token = PtrToken {
+            owner_address,
+            contract: contract.address(),
+            receipt,
+        };
+        let mut instance_to_contract = self.instance_to_contract.write().unwrap();
+        let contracts = instance_to_contract.entry(owner_address).or_default();
+        if let Some(previous) = contracts.last() {
+            warn!(
+                "nft_ptr {:x} initialized again without being destroyed; {} now owns \
+                 its tokens until it's destroyed, then {} again",
+                owner_address,
+                checksum(&contract.address()),
+                checksum(&previous.address())
+            );
+        }
+        contracts.push(contract);
+        Ok(token)
+    }
+
+    // ptr_initialize for many nft_ptrs at once, e.g. everything a program allocates at startup.
+    // Up to config.init_concurrency deploys are in flight at a time. There's one result per
+    // request, in the same order as `requests`; one failing doesn't stop the rest.
+    #[instrument(skip(self, requests), fields(count = requests.len()))]
+    pub async fn ptr_initialize_many(
+        &self,
+        requests: Vec<PtrInitRequest>,
+    ) -> Vec<Result<PtrToken, NftPtrError>> {
+        let mut tokens = Vec::with_capacity(requests.len());
+        for batch in requests.chunks(self.config.init_concurrency.max(1)) {
+            let results = join_all(batch.iter().map(|request| {
+                self.ptr_initialize(
+                    request.owner_address,
+                    request.caller_pc,
+                    &request.ptr_object_type,
+                )
+            }))
+            .await;
+            tokens.extend(results);
+        }
+        tokens
+    }
+
+    // Deploys a different owner contract for nft_ptrs whose demangled object type contains
+    // `pattern` (e.g. "shared_ptr"). Templates are matched in the order they were registered;
+    // anything that matches none of them gets the built-in NftPtrOwner.
+    pub fn register_owner_template(
+        &mut self,
+        pattern: &str,
+        bytecode: &str,
+        abi: &[u8],
+    ) -> Result<(), NftPtrError> {
+        self.owner_templates.push(OwnerTemplate {
+            pattern: pattern.to_string(),
+            artifact: Artifact::new(bytecode, abi)?,
+        });
+        Ok(())
+    }
+
+    // Returns whether `owner_address` was a tracked nft_ptr. Destroying one that isn't (a
+    // double free, or a token moved into it without ptr_initialize) is logged as a warning.
+    // If it was initialized more than once, only the most recent owner contract is forgotten.
+    // With config.destroy_owner_contracts, that contract is also selfdestructed, and an error
+    // means the destroy transaction failed; the nft_ptr is forgotten either way.
+    #[instrument(skip(self))]
+    pub async fn ptr_destroy(&self, owner_address: u64) -> Result<bool, NftPtrError> {
+        if self.is_paused() {
+            debug!("Paused, not destroying nft_ptr {:x}", owner_address);
+            return Ok(false);
+        }
+        self.destroy_ptr(owner_address).await
+    }
+    // ptr_destroy, even while paused.
+    async fn destroy_ptr(&self, owner_address: u64) -> Result<bool, NftPtrError> {
+        let removed = {
+            let mut instance_to_contract = self.instance_to_contract.write().unwrap();
+            match instance_to_contract.get_mut(&owner_address) {
+                Some(contracts) => {
+                    let contract = contracts.pop();
+                    if contracts.is_empty() {
+                        instance_to_contract.remove(&owner_address);
+                    }
+                    contract
+                }
+                None => None,
+            }
+        };
+        let contract = match removed {
+            Some(contract) => contract,
+            None => {
+                warn!("Destroying untracked nft_ptr {:x}", owner_address);
+                return Ok(false);
+            }
+        };
+        let kind = TxKind::PtrDestroy {
+            owner_address,
+            contract: contract.address(),
+        };
+        if self.config.destroy_owner_contracts {
+            let transaction_hash = self
+                .send_token_call(&contract, "destroy", Vec::new(), kind)
+                .await?;
+            detail!(
+                self,
+                tx_hash = ?transaction_hash,
+                "Destroyed owner contract {} for nft_ptr {:x}",
+                checksum(&contract.address()),
+                owner_address
+            );
+        } else {
+            // Otherwise the contract stays on-chain to be inspected later, and the destroy
+            // only gets an audit record.
+            self.write_audit_record(&serde_json::json!({
+                "timestamp_millis": (self.config.clock)() as u64,
+                "kind": kind,
+            }));
+        }
+        Ok(true)
+    }
+    // Overrides NFT_PTR_NUM_CONFIRMATIONS (or the builder's num_confirmations) for every
+    // transaction sent from now on, for embedders that can't set environment variables.
+    pub fn set_num_confirmations(&self, num_confirmations: usize) {
+        self.num_confirmations_override
+            .store(num_confirmations as u64, Ordering::Relaxed);
+    }
+    fn deploy_confirmation_policy(&self) -> ConfirmationPolicy {
+        self.confirmation_policy(self.config.deploy_confirmation_policy)
+    }
+    fn call_confirmation_policy(&self) -> ConfirmationPolicy {
+        self.confirmation_policy(self.config.call_confirmation_policy)
+    }
+    fn confirmation_policy(&self, configured: ConfirmationPolicy) -> ConfirmationPolicy {
+        match self.num_confirmations_override.load(Ordering::Relaxed) {
+            NO_CONFIRMATIONS_OVERRIDE => configured,
+            num_confirmations => ConfirmationPolicy::Blocks(num_confirmations as usize),
+        }
+    }
+    // ptr_destroy for the nft_ptr a PtrToken came from.
+    pub async fn ptr_destroy_token(&self, token: PtrToken) -> Result<bool, NftPtrError> {
+        self.ptr_destroy(token.owner_address).await
+    }
+    // Called with every transaction we send, e.g. to forward moves to a dashboard.
+    pub fn set_tx_listener(&mut self, listener: impl Fn(&TxEvent) + Send + Sync + 'static) {
+        self.tx_listener = Some(Box::new(listener));
+    }
+    // Called as each move_token and ptr_initialize transaction is submitted, mined, and
+    // confirmed or failed; nothing is called in a dry run. A panic in the observer is caught
+    // and logged, so it can't fail the transaction.
+    pub fn set_lifecycle_observer(
+        &mut self,
+        observer: impl Fn(&LifecycleEvent) + Send + Sync + 'static,
+    ) {
+        self.lifecycle_observer = Some(Box::new(observer));
+    }
+    // Tells the lifecycle observer `operation` reached the stage `stage` builds. Transactions
+    // without an operation (and everything, without an observer) skip building the event.
+    fn notify_lifecycle(&self, operation: Option<&TxOperation>, stage: impl FnOnce() -> TxStage) {
+        let (observer, operation) = match (&self.lifecycle_observer, operation) {
+            (Some(observer), Some(operation)) => (observer, operation),
+            _ => return,
+        };
+        let event = LifecycleEvent {
+            operation: operation.clone(),
+            stage: stage(),
+        };
+        if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(|| observer(&event))) {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("(no message)");
+            warn!(
+                "Lifecycle observer panicked on {:?}: {}",
+                event.stage, message
+            );
+        }
+    }
+    // Adds a sent transaction to the history and the audit log, and tells the listener about it.
+    fn record_transaction(&self, receipt: &TransactionReceipt, kind: TxKind) {
+        let entry = HistoryEntry {
+            timestamp_millis: (self.config.clock)() as u64,
+            transaction_hash: receipt.transaction_hash,
+            gas_used: receipt.gas_used,
+            kind: kind.clone(),
+        };
+        self.write_audit_record(&entry);
+        let mut history = self.history.lock().unwrap();
+        history.push_back(entry);
+        while history.len() > self.config.history_limit {
+            history.pop_front();
+        }
+        drop(history);
+        let event = TxEvent {
+            transaction_hash: receipt.transaction_hash,
+            gas_used: receipt.gas_used,
+            kind,
+        };
+        if self.config.verbosity == Verbosity::Structured {
+            info!(target: "nft_ptr::event", "{}", serde_json::to_string(&event).unwrap());
+        }
+        if let Some(listener) = &self.tx_listener {
+            listener(&event);
+        }
+    }
+
+    // Appends `record` to the audit log as a line of JSON. A failed write is only logged:
+    // losing an audit line is better than failing the move it describes.
+    fn write_audit_record(&self, record: &impl serde::Serialize) {
+        let audit_log = match &self.audit_log {
+            Some(audit_log) => audit_log,
+            None => return,
+        };
+        let mut line = serde_json::to_vec(record).unwrap();
+        line.push(b'\n');
+        let mut file = audit_log.lock().unwrap();
+        // One write per line, so a crash can't leave half a record behind.
+        if let Err(err) = file.write_all(&line).and_then(|_| file.flush()) {
+            warn!("Failed to write to the audit log: {}", err);
+        }
+    }
+
+    // While paused, move_token, ptr_initialize and ptr_destroy do nothing (ptr_initialize
+    // returns NftPtrError::Paused, as it has no token to return), so an instrumented
+    // program can switch off the overhead (e.g. from a signal handler) during a hot phase.
+    // The token contract and the tracked nft_ptrs are kept, so tracking picks up where it left off.
+    // It only stores a flag: logging here could take a lock the signal interrupted.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    // Waits until every transaction sent since the last barrier (or shutdown) is `depth` blocks
+    // deep, e.g. to checkpoint a run of moves sent with zero confirmations. Those transactions
+    // are then forgotten, so a later shutdown only waits for ones sent after this.
+    #[instrument(skip(self))]
+    pub async fn barrier(&self, depth: usize) -> Result<(), NftPtrError> {
+        let transactions = std::mem::take(&mut *self.submitted_transactions.lock().unwrap());
+        info!(
+            "Waiting for {} transactions to reach {} confirmations",
+            transactions.len(),
+            depth
+        );
+        for transaction_hash in transactions {
+            self.wait_for_receipt(transaction_hash, ConfirmationPolicy::Blocks(depth), None)
+                .await?;
+        }
+        // Covers every transaction already seen mined, since they're in this block or older.
+        let latest_mined_block = self.latest_mined_block.lock().unwrap().take();
+        if let Some(latest_mined_block) = latest_mined_block {
+            self.wait_for_depth(latest_mined_block, depth).await?;
+        }
+        Ok(())
+    }
+
+    // Waits until every transaction sent so far is `confirmations` blocks deep, then logs a
+    // summary. Short-lived programs should call this before exiting so they don't lose moves.
+    // A failed flush doesn't stop the wait or the summary; the error is the first one.
+    #[instrument(skip(self))]
+    pub async fn shutdown(&self, confirmations: usize) -> Result<(), NftPtrError> {
+        let flushed = self.flush().await;
+        let waited = self.barrier(confirmations).await;
+        let stats = self.stats.lock().unwrap();
+        info!(
+            "Done: {} moves, {} contracts deployed, {} gas used",
+            stats.moves, stats.deploys, stats.gas_used
+        );
+        flushed.and(waited)
+    }
+
+    // The deploys, moves and approvals sent so far, oldest first, for auditing what the
+    // instrumentation did. Only the most recent config.history_limit are kept; use the
+    // audit log for a complete record.
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    // Writes history() to `path` as a JSON array.
+    pub fn export_history(&self, path: &Path) -> Result<(), NftPtrError> {
+        let json = serde_json::to_vec_pretty(&*self.history.lock().unwrap()).unwrap();
+        std::fs::write(path, json)
+            .map_err(|err| NftPtrError::State(format!("can't write {}: {}", path.display(), err)))
+    }
+
+    // Writes the token contract and every tracked nft_ptr's owner contract to `path` as JSON,
+    // so a later run can carry on with them via load_state.
+    pub fn save_state(&self, path: &Path) -> Result<(), NftPtrError> {
+        let (token_contract, extra_token_contracts) = self
+            .token_contracts
+            .split_first()
+            .ok_or(NftPtrError::NotInitialized)?;
+        let instance_to_contract = self.instance_to_contract.read().unwrap();
+        SavedState {
+            token_contract: token_contract.address(),
+            extra_token_contracts: extra_token_contracts
+                .iter()
+                .map(|contract| contract.address())
+                .collect(),
+            nft_ptrs: instance_to_contract
+                .iter()
+                .map(|(owner_address, contracts)| {
+                    (
+                        state::format_ptr_address(*owner_address),
+                        contracts.last().unwrap().address(),
+                    )
+                })
+                .collect(),
+            shadowed_nft_ptrs: instance_to_contract
+                .iter()
+                .filter(|(_, contracts)| contracts.len() > 1)
+                .map(|(owner_address, contracts)| {
+                    (
+                        state::format_ptr_address(*owner_address),
+                        contracts[..contracts.len() - 1]
+                            .iter()
+                            .map(|contract| contract.address())
+                            .collect(),
+                    )
+                })
+                .collect(),
+        }
+        .write(path)
+    }
+
+    // Reattaches to the contracts from a save_state file. Call it before initialize, which
+    // then reuses the saved token contract instead of deploying a new one.
+    pub fn load_state(&mut self, path: &Path) -> Result<(), NftPtrError> {
+        let saved = SavedState::read(path)?;
+        let mut instance_to_contract: HashMap<u64, Vec<Contract<T>>> = HashMap::new();
+        for (key, address) in &saved.nft_ptrs {
+            let owner_address = state::parse_ptr_address(key)?;
+            let shadowed = saved.shadowed_nft_ptrs.get(key).into_iter().flatten();
+            let contracts = instance_to_contract.entry(owner_address).or_default();
+            for address in shadowed.chain(std::iter::once(address)) {
+                contracts.push(Contract::new(
+                    self.web3.eth(),
+                    *address,
+                    self.owner_artifact.abi.clone(),
+                ));
+            }
+        }
+        let mut token_contracts = Vec::new();
+        for address in std::iter::once(&saved.token_contract).chain(&saved.extra_token_contracts) {
+            token_contracts.push(Contract::new(
+                self.web3.eth(),
+                *address,
+                self.token_artifact.abi.clone(),
+            ));
+        }
+        self.token_contracts = token_contracts;
+        self.token_deploy_receipts.clear();
+        *self.instance_to_contract.write().unwrap() = instance_to_contract;
+        info!(
+            "Loaded {} token contracts and {} nft_ptrs from {}",
+            self.token_contracts.len(),
+            saved.nft_ptrs.len(),
+            path.display()
+        );
+        Ok(())
+    }
+
+    // Waits for the moves still pending from fire_and_forget mode, then destroys every nft_ptr
+    // still being tracked, as if each had gone out of scope, even while paused. With
+    // config.destroy_owner_contracts each owner contract is selfdestructed on-chain.
+    // Drop can't be async, so an NftPtrLib that's dropped without calling this leaves its
+    // owner contracts dangling, with nothing recording that their nft_ptrs were freed.
+    // Failures are logged; every nft_ptr is forgotten either way.
+    #[instrument(skip(self))]
+    pub async fn teardown(self) {
+        if let Err(err) = self.flush().await {
+            warn!("Pending moves failed before teardown: {}", err);
+        }
+        // An address initialized more than once is destroyed once per owner contract.
+        let owner_addresses: Vec<u64> = self
+            .instance_to_contract
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|(owner_address, contracts)| {
+                std::iter::repeat(*owner_address).take(contracts.len())
+            })
+            .collect();
+        info!("Tearing down {} nft_ptrs", owner_addresses.len());
+        for owner_address in owner_addresses {
+            if let Err(err) = self.destroy_ptr(owner_address).await {
+                warn!("Failed to destroy nft_ptr {:x}: {}", owner_address, err);
+            }
+        }
+    }
+    // Etherscan's page for an account or contract, or None if the network we're on (see
+    // initialize) has no Etherscan.
+    pub fn etherscan_address_url(&self, address: Address) -> Option<String> {
+        Some(format!("{}/address/{:#x}", self.etherscan_base()?, address))
+    }
+
+    // Etherscan's page for a token contract, e.g. the token contract or an owner contract.
+    pub fn etherscan_token_url(&self, contract: Address) -> Option<String> {
+        Some(format!("{}/token/{:#x}", self.etherscan_base()?, contract))
+    }
+
+    // OpenSea's page for the token for the object at `value`, once initialize has deployed
+    // the token contract.
+    pub fn opensea_asset_url(&self, value: u64) -> Option<String> {
+        let base = match self.network_id {
+            5 => "https://testnets.opensea.io/assets/goerli",
+            _ => return None,
+        };
+        Some(format!(
+            "{}/{:#x}/{:#x}",
+            base,
+            self.token_contract_for(value).ok()?.address(),
+            value
+        ))
+    }
+
+    fn etherscan_base(&self) -> Option<&'static str> {
+        match self.network_id {
+            5 => Some("https://goerli.etherscan.io"),
+            _ => None,
+        }
+    }
+
+    // Snapshots the chain with evm_snapshot (Anvil, Hardhat and Ganache only), so a test can
+    // deploy and move things and then roll the chain back with revert.
+    #[cfg(any(test, feature = "test-util"))]
+    pub async fn snapshot(&self) -> Result<U256, NftPtrError> {
+        let id = self
+            .rpc(web3::Transport::execute(
+                self.web3.transport(),
+                "evm_snapshot",
+                vec![],
+            ))
+            .await?;
+        serde_json::from_value(id)
+            .map_err(|err| web3::Error::Decoder(format!("evm_snapshot: {}", err)).into())
+    }
+
+    // Rolls the chain back to a snapshot; returns false if the node didn't know it. Only the
+    // chain goes back: contracts deployed since are still tracked, so make a new lib (or
+    // load_state) to match.
+    #[cfg(any(test, feature = "test-util"))]
+    pub async fn revert(&self, id: U256) -> Result<bool, NftPtrError> {
+        let reverted = self
+            .rpc(web3::Transport::execute(
+                self.web3.transport(),
+                "evm_revert",
+                vec![serde_json::json!(id)],
+            ))
+            .await?;
+        // Our next nonce may have been rolled back too.
+        *self.next_nonce.lock().await = None;
+        serde_json::from_value(reverted)
+            .map_err(|err| web3::Error::Decoder(format!("evm_revert: {}", err)).into())
+    }
+}
+
+impl<T: CloseTransport> NftPtrLib<T> {
+    // Checks the moves still pending from fire_and_forget mode, then closes the connection
+    // to the node. With IPC, prefer this to just dropping the lib: the socket stays open
+    // until every clone of the transport is gone, which a clone kept elsewhere (e.g. from
+    // web3()) can put off indefinitely. A no-op for HTTP, apart from the flush.
+    #[instrument(skip(self))]
+    pub async fn close(self) -> Result<(), NftPtrError> {
+        let flushed = self.flush().await;
+        self.web3.transport().close();
+        flushed
+    }
+}
+
+impl<T> NftPtrLib<T>
+where
+    T: web3::Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    // Starts a task on the current tokio runtime that does move_token, ptr_initialize and
+    // ptr_destroy for the returned handle in the background. Up to `queue_size` operations
+    // wait in line; past that, queueing waits for the worker to catch up.
+    pub fn spawn_worker(self: &Arc<Self>, queue_size: usize) -> WorkerHandle {
+        worker::spawn(self.clone(), queue_size)
+    }
+}
+
+// Enough to tell whether initialize ran and what it's connected to; leaves out the signing
+// key and the contract ABIs.
+impl<T: web3::Transport> fmt::Debug for NftPtrLib<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NftPtrLib")
+            .field("account", &self.account)
+            .field(
+                "token_contracts",
+                &self
+                    .token_contracts
+                    .iter()
+                    .map(|contract| contract.address())
+                    .collect::<Vec<_>>(),
+            )
+            .field("network_id", &self.network_id)
+            .field(
+                "tracked_ptrs",
+                &self.instance_to_contract.read().unwrap().len(),
+            )
+            .field(
+                "deploy_confirmation_policy",
+                &self.deploy_confirmation_policy(),
+            )
+            .field("call_confirmation_policy", &self.call_confirmation_policy())
+            .field("signs_locally", &self.account_private_key.is_some())
+            .field("dry_run", &self.config.dry_run)
+            .field("paused", &self.is_paused())
+            .finish()
+    }
+}
+
+impl<T: web3::Transport> fmt::Display for NftPtrLib<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.token_contracts.first() {
+            Some(contract) => write!(
+                f,
+                "nft_ptr token {} on network {} from {}, tracking {} nft_ptrs",
+                checksum(&contract.address()),
+                self.network_id,
+                checksum(&self.account),
+                self.instance_to_contract.read().unwrap().len()
+            ),
+            None => write!(f, "nft_ptr (not initialized)"),
+        }
+    }
+}
+
+// Connects over the node's IPC socket at `path` (e.g. the NFT_PTR_IPC that
+// make_nft_ptr_lib reads).
+pub async fn make_nft_ptr_lib_ipc(
+    path: impl AsRef<Path>,
+) -> Result<NftPtrLib<ReconnectingIpc>, NftPtrError> {
+    NftPtrLib::try_new(make_ipc_transport(path.as_ref()).await?)
+}
+
+pub fn make_nft_ptr_lib_localhost() -> Result<NftPtrLib<web3::transports::Http>, NftPtrError> {
+    NftPtrLib::try_new(make_http_transport(DEFAULT_HTTP_ENDPOINT)?)
+}
+
+pub type NftPtrLibTransport = web3::transports::Either<web3::transports::Http, ReconnectingIpc>;
+
+pub async fn make_nft_ptr_lib() -> Result<NftPtrLib<NftPtrLibTransport>, NftPtrError> {
+    let ipc_path = std::env::var("NFT_PTR_IPC");
+    let transport = if ipc_path.is_ok() {
+        NftPtrLibTransport::Right(make_ipc_transport(Path::new(&ipc_path.unwrap())).await?)
+    } else {
+        NftPtrLibTransport::Left(make_http_transport(
+            &std::env::var("NFT_PTR_HTTP").unwrap_or_else(|_| DEFAULT_HTTP_ENDPOINT.to_string()),
+        )?)
+    };
+    NftPtrLib::try_new(transport)
+}
+
+// Checks the socket exists first, since connecting to a missing one only gives an
+// I/O error that doesn't say which path it tried.
+async fn make_ipc_transport(path: &Path) -> Result<ReconnectingIpc, NftPtrError> {
+    if !path.exists() {
+        return Err(NftPtrError::BadEndpoint(format!(
+            "IPC socket {} doesn't exist",
+            path.display()
+        )));
+    }
+    Ok(ReconnectingIpc::new(path).await?)
+}
+
+// Nodes that can fork mainnet locally, by their web3_clientVersion.
+fn is_dev_node(client_version: &str) -> bool {
+    [
+        "anvil/",
+        "HardhatNetwork/",
+        "EthereumJS TestRPC/",
+        "Ganache/",
+    ]
+    .iter()
+    .any(|prefix| client_version.starts_with(prefix))
+}
+
+// Checks the URL up front so a typo in NFT_PTR_HTTP is reported as such.
+fn make_http_transport(endpoint: &str) -> Result<web3::transports::Http, NftPtrError> {
+    match url::Url::parse(endpoint) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+        _ => return Err(NftPtrError::BadEndpoint(endpoint.to_string())),
+    }
+    web3::transports::Http::new(endpoint)
+        .map_err(|_| NftPtrError::BadEndpoint(endpoint.to_string()))
+}
+
+// Milliseconds since the Unix epoch; the default clock for contract names.
+pub(crate) fn system_clock_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+// The id of the token for the object at `value`. Always derive ids through this, in case
+// they stop being just the address.
+pub fn token_id_for(value: u64) -> U256 {
+    U256::from(value)
+}
+
+// mintOrMove(owner, previousOwner, tokenId, tokenURI, callerPC), as move_token calls it.
+fn mint_or_move_args(
+    owner_address: u64,
+    owner_contract: Address,
+    previous_owner_contract: Address,
+    value: u64,
+    caller_pc_lineinfo: &str,
+    token_uri_encoded: String,
+) -> Vec<ethabi::Token> {
+    (
+        owner_contract,
+        previous_owner_contract,
+        token_id_for(value),
+        token_uri_encoded,
+        format!("{:x} {}", owner_address, caller_pc_lineinfo),
+    )
+        .into_tokens()
+}
+
+// The part of a token's URI after the base URI: "<value> <type>", then any metadata as
+// "&key=value" pairs, all percent-encoded.
+fn encode_token_uri(value: u64, object_type: &str, metadata: &[(&str, &str)]) -> String {
+    let encode = |s: &str| {
+        percent_encoding::utf8_percent_encode(s, percent_encoding::NON_ALPHANUMERIC).to_string()
+    };
+    let mut token_uri = encode(&format!("{:x} {}", value, object_type));
+    for (key, value) in metadata {
+        token_uri += &format!("&{}={}", encode(key), encode(value));
+    }
+    token_uri
+}
+
+// An address in EIP-55 mixed case, as explorers and wallets show it: each hex letter is
+// uppercase if the matching nibble of keccak256(lowercase hex) is 8 or more.
+fn checksum(addr: &Address) -> String {
+    let lower = hex::encode(addr.as_bytes());
+    let hash = web3::signing::keccak256(lower.as_bytes());
+    let mixed: String = lower
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (hash[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0xf;
+            if nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("0x{}", mixed)
+}
+
+// Stands in for a token URI that's too long: the value, and keccak256 of the full URI.
+fn hashed_token_uri(value: u64, token_uri: &str) -> String {
+    format!(
+        "{:x}&hash=0x{}",
+        value,
+        hex::encode(web3::signing::keccak256(token_uri.as_bytes()))
+    )
+}
+
+// Multiplies in thousandths, which is plenty of precision for a fee multiplier.
+fn scale_gas_price(gas_price: U256, multiplier: f64) -> U256 {
+    gas_price * U256::from((multiplier.max(0.0) * 1000.0).round() as u64) / 1000
+}
+
+fn scale_gas_limit(gas: u64, multiplier: f64) -> U256 {
+    U256::from(((gas as f64 * multiplier.max(0.0)).round() as u64).min(MAX_GAS_LIMIT))
+}
+
+fn expand_base_uri(template: &str, contract: Address, network_id: u32) -> String {
+    template
+        .replace("{contract}", &format!("{:#x}", contract))
+        .replace("{chain}", &network_id.to_string())
+}
+
+// The address a contract deployed by `sender` with `nonce` ends up at:
+// the last 20 bytes of keccak256(rlp([sender, nonce])).
+fn create_address(sender: Address, nonce: U256) -> Address {
+    let mut nonce_bytes = [0u8; 32];
+    nonce.to_big_endian(&mut nonce_bytes);
+    let nonce_bytes = &nonce_bytes[nonce_bytes.iter().take_while(|b| **b == 0).count()..];
+    let mut rlp = vec![0x94];
+    rlp.extend_from_slice(sender.as_bytes());
+    match nonce_bytes {
+        [] => rlp.push(0x80),
+        [byte] if *byte < 0x80 => rlp.push(*byte),
+        _ => {
+            rlp.push(0x80 + nonce_bytes.len() as u8);
+            rlp.extend_from_slice(nonce_bytes);
+        }
+    }
+    rlp.insert(0, 0xc0 + rlp.len() as u8);
+    Address::from_slice(&web3::signing::keccak256(&rlp)[12..])
+}
+
+// topic[0] of ERC721's Transfer(address indexed from, address indexed to, uint256 indexed tokenId)
+fn transfer_event_topic() -> H256 {
+    H256::from(web3::signing::keccak256(
+        b"Transfer(address,address,uint256)",
+    ))
+}
+
+fn format_gas_used(receipt: &TransactionReceipt) -> String {
+    receipt
+        .gas_used
+        .map_or_else(|| "unknown".to_string(), |gas| gas.to_string())
+}
+
+// Symbolicates a batch of PCs, e.g. from a recorded run, formatting each like the caller PCs
+// stored with every move. backtrace keeps the parsed debug info of each loaded object
+// around between resolves, so a batch only pays for that once, and repeated PCs
+// (the usual case: a few hot call sites) are only resolved once.
+pub fn resolve_pcs(pcs: &[u64]) -> Vec<String> {
+    resolve_each_pc_once(pcs, |pc| string_for_pc_addr(pc, DemangleMode::Full))
+}
+
+// resolve_pcs for PCs captured in another process, against the object file at `path` that
+// was loaded at `base_address` there. Its debug info is only parsed once for the batch.
+pub fn resolve_pcs_in_object(
+    pcs: &[u64],
+    path: &Path,
+    base_address: u64,
+) -> Result<Vec<String>, NftPtrError> {
+    let context = ObjectContext::load(path, base_address)?;
+    Ok(resolve_each_pc_once(pcs, |pc| {
+        context.resolve(pc, DemangleMode::Full).to_string()
+    }))
+}
+
+fn resolve_each_pc_once(pcs: &[u64], resolve: impl Fn(u64) -> String) -> Vec<String> {
+    let mut resolved: HashMap<u64, String> = HashMap::new();
+    pcs.iter()
+        .map(|pc| resolved.entry(*pc).or_insert_with(|| resolve(*pc)).clone())
+        .collect()
+}
+
+// The crate a PC in this process is in, e.g. "mycrate" for mycrate::module::func, for
+// grouping moves by where they came from. None for anything that isn't a Rust function,
+// such as the C++ that nft_ptr usually instruments.
+pub fn crate_name_for_pc(pc: u64) -> Option<String> {
+    let mut crate_name = None;
+    let mut resolved = false;
+    backtrace::resolve(pc as _, |symbol| {
+        if resolved {
+            return;
+        }
+        if let Some(name) = symbol.name().and_then(|name| name.as_str()) {
+            resolved = true;
+            crate_name = crate_name_for_symbol(name);
+        }
+    });
+    crate_name
+}
+
+// The first path segment of a mangled Rust symbol. For a trait impl
+// (<mycrate::Foo as core::fmt::Debug>::fmt) that's the implementing type's crate.
+fn crate_name_for_symbol(mangled: &str) -> Option<String> {
+    let demangled = rustc_demangle::try_demangle(mangled).ok()?;
+    // Legacy Rust symbols share C++'s _ZN prefix; only Rust's end in a hash, which {:#} drops.
+    if !mangled.starts_with("_R") && demangled.to_string() == format!("{:#}", demangled) {
+        return None;
+    }
+    let path = format!("{:#}", demangled);
+    let path = path.trim_start_matches(|c| c == '<' || c == '&' || c == '*');
+    let path = path
+        .strip_prefix("mut ")
+        .or_else(|| path.strip_prefix("const "))
+        .unwrap_or(path);
+    let crate_name = path.split("::").next()?;
+    if crate_name.is_empty()
+        || !crate_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return None;
+    }
+    Some(crate_name.to_string())
+}
+
+fn string_for_pc_addr(pc_addr: u64, mode: DemangleMode) -> String {
+    caller_location(pc_addr, mode).to_string()
+}
+
+// Resolves a PC in this process with the first symbol that has a name.
+fn caller_location(pc_addr: u64, mode: DemangleMode) -> CallerLocation {
+    let mut location = CallerLocation {
+        pc: pc_addr,
+        symbol: None,
+        file: None,
+        line: None,
+    };
+    backtrace::resolve(pc_addr as _, |symbol| {
+        if location.symbol.is_some() {
+            return;
+        }
+        let name = match symbol.name().and_then(|name| name.as_str()) {
+            Some(name) => name,
+            None => return,
+        };
+        location.symbol = Some(demangle_cpp(name, mode).into_owned());
+        location.file = symbol
+            .filename()
+            .map(|file| file.to_string_lossy().into_owned());
+        location.line = symbol.lineno();
+    });
+    location
+}
+
+fn demangle_cpp(typename: &str, mode: DemangleMode) -> Cow<'_, str> {
+    // This runs on every move, and most names are already readable, so only pay for the
+    // demangler when it could do something.
+    if !looks_mangled(typename) {
+        return Cow::Borrowed(typename);
+    }
+    if typename.starts_with("_R") {
+        if let Ok(demangled) = rustc_demangle::try_demangle(typename) {
+            return Cow::Owned(format!("{:#}", demangled));
+        }
+    }
+    // I could just call abi::__cxx_demangle in the C++, but lol WRITE IT IN RUST
+    let demangled = cpp_demangle::Symbol::new(typename);
+    if let Ok(demangled_out) = demangled {
+        return match mode {
+            DemangleMode::Full => Cow::Owned(demangled_out.to_string()),
+            DemangleMode::Compact => {
+                let options = cpp_demangle::DemangleOptions::new()
+                    .no_params()
+                    .no_return_type();
+                match demangled_out.demangle(&options) {
+                    Ok(compact) => Cow::Owned(compact),
+                    Err(_) => Cow::Owned(demangled_out.to_string()),
+                }
+            }
+        };
+    }
+    Cow::Borrowed(typename)
+}
+
+// Whether `name` could be a mangled symbol (C++ or Rust's v0 scheme) or a typeid() name,
+// rather than something that's already readable like "MyClass" or "std::vector<int>".
+fn looks_mangled(name: &str) -> bool {
+    if name.starts_with("_Z") || name.starts_with("_R") {
+        return true;
+    }
+    // typeid() names are bare type encodings: a class name always comes with its length
+    // (e.g. "P3Cow"), and the ones without are short builtins like "i", "PKc" or "Ss".
+    !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        && (name.bytes().any(|b| b.is_ascii_digit()) || name.len() <= 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn demangle_cpp_example() {
+        assert_eq!(demangle_cpp("P3Cow", DemangleMode::Full), "Cow*");
+    }
+    #[test]
+    fn demangle_cpp_passes_through_readable_names() {
+        assert!(matches!(
+            demangle_cpp("MyClass", DemangleMode::Full),
+            Cow::Borrowed("MyClass")
+        ));
+        assert!(matches!(
+            demangle_cpp("", DemangleMode::Full),
+            Cow::Borrowed("")
+        ));
+        assert!(matches!(
+            demangle_cpp("std::vector<int>", DemangleMode::Full),
+            Cow::Borrowed("std::vector<int>")
+        ));
+        assert_eq!(demangle_cpp("i", DemangleMode::Full), "int");
+    }
+    #[test]
+    fn demangle_compact_drops_params() {
+        let symbol = "_ZN3foo3barEiPKc";
+        assert_eq!(
+            demangle_cpp(symbol, DemangleMode::Full),
+            "foo::bar(int, char const*)"
+        );
+        assert_eq!(demangle_cpp(symbol, DemangleMode::Compact), "foo::bar");
+        assert_eq!(demangle_cpp("P3Cow", DemangleMode::Compact), "Cow*");
+    }
+    #[test]
+    fn resolve_pcs_matches_single_pc() {
+        let pc = resolve_pcs as usize as u64;
+        let pcs = [1, pc, 1];
+        let expected: Vec<String> = pcs
+            .iter()
+            .map(|pc| string_for_pc_addr(*pc, DemangleMode::Full))
+            .collect();
+        assert_eq!(resolve_pcs(&pcs), expected);
+        assert_eq!(resolve_pcs(&[1])[0], "1");
+    }
+    #[test]
+    fn crate_name_for_symbol_examples() {
+        assert_eq!(
+            crate_name_for_symbol("_ZN7mycrate6module4func17h0123456789abcdefE").as_deref(),
+            Some("mycrate")
+        );
+        // mycrate::func::{{closure}}
+        assert_eq!(
+            crate_name_for_symbol(
+                "_ZN7mycrate4func28_$u7b$$u7b$closure$u7d$$u7d$17h0123456789abcdefE"
+            )
+            .as_deref(),
+            Some("mycrate")
+        );
+        // <mycrate::Foo as core::fmt::Debug>::fmt
+        assert_eq!(
+            crate_name_for_symbol(
+                "_ZN49_$LT$mycrate..Foo$u20$as$u20$core..fmt..Debug$GT$3fmt17h0123456789abcdefE"
+            )
+            .as_deref(),
+            Some("mycrate")
+        );
+        // C++: Cow::moo()
+        assert_eq!(crate_name_for_symbol("_ZN3Cow3mooEv"), None);
+        assert_eq!(crate_name_for_symbol("main"), None);
+        assert_eq!(
+            crate_name_for_pc(crate_name_for_pc as usize as u64).as_deref(),
+            Some("nft_ptr_lib")
+        );
+    }
+    #[test]
+    fn object_symbolizer_needs_readable_file() {
+        let path = std::env::temp_dir().join("nft_ptr_no_such_binary");
+        assert!(matches!(
+            NftPtrLibBuilder::new()
+                .symbolizer(Symbolizer::ObjectFile {
+                    path: path.clone(),
+                    base_address: 0,
+                })
+                .build(MockTransport::new()),
+            Err(NftPtrError::Symbolizer(_))
+        ));
+        assert!(matches!(
+            resolve_pcs_in_object(&[0x1234], &path, 0),
+            Err(NftPtrError::Symbolizer(_))
+        ));
+    }
+    #[test]
+    fn caller_location_display() {
+        let mut location = CallerLocation {
+            pc: 0x1234,
+            symbol: None,
+            file: None,
+            line: None,
+        };
+        assert_eq!(location.to_string(), "1234");
+        location.symbol = Some("main".to_string());
+        assert_eq!(location.to_string(), "main");
+        location.file = Some("/src/example/main.cpp".to_string());
+        location.line = Some(12);
+        assert_eq!(location.to_string(), "main (main.cpp:12)");
+    }
+    #[test]
+    fn encode_token_uri_metadata() {
+        assert_eq!(encode_token_uri(0xabcd, "Cow*", &[]), "abcd%20Cow%2A");
+        assert_eq!(
+            encode_token_uri(0xabcd, "Cow*", &[("thread", "3"), ("tag", "a b")]),
+            "abcd%20Cow%2A&thread=3&tag=a%20b"
+        );
+    }
+    #[test]
+    fn checksum_matches_eip55() {
+        // From the EIP-55 test cases.
+        for expected in [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+            "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        ] {
+            let addr: Address = expected.parse().unwrap();
+            assert_eq!(checksum(&addr), expected);
+        }
+    }
+    #[test]
+    fn scale_gas_price_example() {
+        let gwei = U256::from(1_000_000_000u64);
+        assert_eq!(scale_gas_price(gwei, 1.0), gwei);
+        assert_eq!(scale_gas_price(gwei, 1.5), U256::from(1_500_000_000u64));
+        assert_eq!(scale_gas_price(gwei, -1.0), U256::zero());
+    }
+    #[test]
+    fn scale_gas_limit_clamps() {
+        assert_eq!(scale_gas_limit(220_000, 1.0), U256::from(220_000));
+        assert_eq!(scale_gas_limit(220_000, 1.5), U256::from(330_000));
+        assert_eq!(scale_gas_limit(6_000_000, 100.0), U256::from(MAX_GAS_LIMIT));
+    }
+    #[test]
+    fn expand_base_uri_placeholders() {
+        let contract: Address = "0xcd234a471b72ba2f1ccf0a70fcaba648a5eecd8d"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            expand_base_uri("https://example.com/{chain}/{contract}/", contract, 5),
+            "https://example.com/5/0xcd234a471b72ba2f1ccf0a70fcaba648a5eecd8d/"
+        );
+        assert_eq!(
+            expand_base_uri(TOKEN_BASE_URI, contract, 5),
+            "https://nft-ptr.notnow.dev/?"
+        );
+    }
+    #[test]
+    fn create_address_example() {
+        let sender: Address = "0x6ac7ea33f8831ea9dcc53393aaa88b25a785dbf0"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            create_address(sender, U256::zero()),
+            "0xcd234a471b72ba2f1ccf0a70fcaba648a5eecd8d"
+                .parse()
+                .unwrap()
+        );
+        assert_eq!(
+            create_address(sender, U256::one()),
+            "0x343c43a37d37dff08ae8c4a11544c718abb4fcf8"
+                .parse()
+                .unwrap()
+        );
+    }
+    #[test]
+    fn decode_revert_reason_example() {
+        let mut data = vec![0x08, 0xc3, 0x79, 0xa0];
+        data.extend(ethabi::encode(&[ethabi::Token::String(
+            "NftPtrToken: must be the owner to mintOrMove".to_string(),
+        )]));
+        assert_eq!(
+            error::decode_revert_reason(&data).as_deref(),
+            Some("NftPtrToken: must be the owner to mintOrMove")
+        );
+        assert_eq!(error::decode_revert_reason(&data[4..]), None);
+    }
+    #[test]
+    fn make_http_transport_rejects_bad_endpoints() {
+        assert!(make_http_transport("http://127.0.0.1:7545").is_ok());
+        assert!(matches!(
+            make_http_transport("127.0.0.1:7545"),
+            Err(NftPtrError::BadEndpoint(_))
+        ));
+        assert!(matches!(
+            make_http_transport("not a url"),
+            Err(NftPtrError::BadEndpoint(_))
+        ));
+    }
+    #[tokio::test]
+    async fn make_nft_ptr_lib_ipc_rejects_missing_socket() {
+        assert!(matches!(
+            make_nft_ptr_lib_ipc("/nonexistent/geth.ipc").await,
+            Err(NftPtrError::BadEndpoint(_))
+        ));
+    }
+    #[test]
+    fn parse_private_key_formats() {
+        let key = "de4e4cafe521bd9c5830e25ef9799d2ac6feadf11430b069821c12ae30d70f54";
+        assert!(builder::parse_private_key(key).is_ok());
+        assert_eq!(
+            builder::parse_private_key(&format!("0x{}", key)).unwrap(),
+            builder::parse_private_key(key).unwrap()
+        );
+        assert!(matches!(
+            builder::parse_private_key("0x1234"),
+            Err(NftPtrError::Keystore(_))
+        ));
+        assert!(matches!(
+            builder::parse_private_key(&"zz".repeat(32)),
+            Err(NftPtrError::Keystore(_))
+        ));
+    }
+    // The arguments of each eth_sendTransaction so far that calls `function` on the token
+    // contract, oldest first.
+    fn decoded_calls(transport: &MockTransport, function: &str) -> Vec<Vec<ethabi::Token>> {
+        let abi = Artifact::embedded_token().abi;
+        let function = abi.function(function).unwrap();
+        transport
+            .requests_for("eth_sendTransaction")
+            .iter()
+            .filter_map(|params| {
+                let data = hex::decode(&params[0]["data"].as_str()?[2..]).ok()?;
+                if data.len() < 4 || data[..4] != function.short_signature() {
+                    return None;
+                }
+                Some(function.decode_input(&data[4..]).unwrap())
+            })
+            .collect()
+    }
+    // The one unlocked account on mock_chain.
+    fn test_account() -> Address {
+        Address::from_low_u64_be(0x1234)
+    }
+    // Where every contract deployed on mock_chain lands.
+    fn test_contract() -> Address {
+        Address::from_low_u64_be(0x5678)
+    }
+    fn mock_chain() -> MockTransport {
+        MockTransport::with_chain(1337, test_account(), test_contract())
+    }
+    // Settings every mock test shares: nothing waits for confirmations or gets skipped, and
+    // token URIs have a known run id.
+    fn test_builder() -> NftPtrLibBuilder {
+        NftPtrLibBuilder::new()
+            .num_confirmations(0)
+            .skip_redundant_moves(false)
+            .dry_run(false)
+            .run_id("test")
+    }
+    fn mock_lib(transport: &MockTransport) -> NftPtrLib<MockTransport> {
+        test_builder().build(transport.clone()).unwrap()
+    }
+    // mock_lib on mock_chain, with its token contract already deployed.
+    async fn initialized_lib() -> (MockTransport, NftPtrLib<MockTransport>) {
+        let transport = mock_chain();
+        let mut lib = mock_lib(&transport);
+        lib.initialize().await.unwrap();
+        (transport, lib)
+    }
+    #[tokio::test]
+    async fn initialize_deploys_token_contract() {
+        let account = test_account();
+        let token_address = test_contract();
+        let (transport, lib) = initialized_lib().await;
+        assert_eq!(lib.account, account);
+        assert_eq!(lib.token_contracts[0].address(), token_address);
+        let sent = transport.requests_for("eth_sendTransaction");
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][0]["from"], serde_json::json!(account));
+        assert!(sent[0][0].get("to").is_none());
+        let data = sent[0][0]["data"].as_str().unwrap();
+        let code = Artifact::embedded_token().code;
+        assert!(data[2..].starts_with(&hex::encode(code)));
+    }
+    #[tokio::test]
+    async fn move_token_sends_mint_or_move() {
+        let account = test_account();
+        let token_address = test_contract();
+        let (transport, lib) = initialized_lib().await;
+        let token_id = lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        assert_eq!(token_id, U256::from(0xabcd));
+        let sent = transport.requests_for("eth_sendTransaction");
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1][0]["to"], serde_json::json!(token_address));
+        let calls = decoded_calls(&transport, "mintOrMove");
+        assert_eq!(calls.len(), 1);
+        let args = &calls[0];
+        // Neither address is a tracked nft_ptr, so both fall back to our account.
+        assert_eq!(args[0], ethabi::Token::Address(account));
+        assert_eq!(args[1], ethabi::Token::Address(account));
+        assert_eq!(args[2], ethabi::Token::Uint(U256::from(0xabcd)));
+        assert_eq!(
+            args[3],
+            ethabi::Token::String("abcd%20Cow%2A&run=test".to_string())
+        );
+        let history = lib.history();
+        assert_eq!(history.len(), 2);
+        assert!(matches!(history[0].kind, TxKind::DeployToken { .. }));
+        assert!(matches!(
+            history[1].kind,
+            TxKind::MoveToken { value: 0xabcd, .. }
+        ));
+    }
+    #[tokio::test]
+    async fn tx_listener_gets_gas_used() {
+        let transport = mock_chain();
+        let mut lib = mock_lib(&transport);
+        let events = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let listener_events = events.clone();
+        lib.set_tx_listener(move |event| listener_events.lock().unwrap().push(event.clone()));
+        lib.initialize().await.unwrap();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].gas_used, Some(U256::from(0x5208)));
+        assert!(
+            matches!(&events[1].kind, TxKind::MoveToken { object_type, .. } if object_type == "Cow*")
+        );
+    }
+    #[tokio::test]
+    async fn lifecycle_observer_sees_each_stage() {
+        let transport = mock_chain();
+        let mut lib = mock_lib(&transport);
+        let events = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let observer_events = events.clone();
+        lib.set_lifecycle_observer(move |event| {
+            observer_events.lock().unwrap().push(event.clone());
+            panic!("observer bug");
+        });
+        lib.initialize().await.unwrap();
+        // The panics don't reach the move.
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        let events = events.lock().unwrap();
+        // Nothing for the token contract deploy.
+        assert_eq!(events.len(), 3);
+        for event in events.iter() {
+            assert_eq!(
+                event.operation,
+                TxOperation::MoveToken {
+                    value: 0xabcd,
+                    owner_address: 0x1000,
+                    previous_owner_address: 0,
+                }
+            );
+        }
+        assert!(matches!(events[0].stage, TxStage::Submitted { .. }));
+        assert!(matches!(
+            events[1].stage,
+            TxStage::Mined {
+                block_number: 1,
+                ..
+            }
+        ));
+        assert!(matches!(
+            events[2].stage,
+            TxStage::Confirmed {
+                gas_used: Some(_),
+                ..
+            }
+        ));
+    }
+    #[tokio::test]
+    async fn ptr_destroy_can_destroy_owner_contract() {
+        let contract_address = test_contract();
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .destroy_owner_contracts(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        lib.ptr_initialize(0x1000, 0, "int").await.unwrap();
+        assert!(lib.ptr_destroy(0x1000).await.unwrap());
+        assert!(!lib.ptr_destroy(0x1000).await.unwrap());
+        let sent = transport.requests_for("eth_sendTransaction");
+        // The token contract, the owner contract, and one destroy.
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[2][0]["to"], serde_json::json!(contract_address));
+        let data = hex::decode(&sent[2][0]["data"].as_str().unwrap()[2..]).unwrap();
+        let destroy = lib.owner_artifact.abi.function("destroy").unwrap();
+        assert_eq!(data, destroy.short_signature());
+        assert!(matches!(
+            lib.history()[2].kind,
+            TxKind::PtrDestroy {
+                owner_address: 0x1000,
+                ..
+            }
+        ));
+    }
+    #[tokio::test]
+    async fn teardown_flushes_then_destroys_owner_contracts() {
+        let contract_address = test_contract();
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .fire_and_forget(true)
+            .destroy_owner_contracts(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        lib.ptr_initialize(0x1000, 0, "int").await.unwrap();
+        lib.ptr_initialize(0x1000, 0, "int").await.unwrap();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        // Teardown still destroys everything while paused.
+        lib.set_paused(true);
+        assert!(matches!(
+            lib.ptr_initialize(0x2000, 0, "int").await,
+            Err(NftPtrError::Paused)
+        ));
+        lib.teardown().await;
+        let requests = transport.requests();
+        let sends: Vec<usize> = (0..requests.len())
+            .filter(|&i| requests[i].0 == "eth_sendTransaction")
+            .collect();
+        // The token contract, two owner contracts, the move, and a destroy for each owner
+        // contract.
+        assert_eq!(sends.len(), 6);
+        for &i in &sends[4..] {
+            assert_eq!(requests[i].1[0]["to"], serde_json::json!(contract_address));
+        }
+        // The pending move's receipt is checked before anything is destroyed.
+        assert!(requests[sends[3]..sends[4]]
+            .iter()
+            .any(|(method, _)| method == "eth_getTransactionReceipt"));
+    }
+    #[tokio::test]
+    async fn burn_token_calls_burn() {
+        let token_address = test_contract();
+        let (transport, lib) = initialized_lib().await;
+        lib.burn_token(0xabcd).await.unwrap();
+        let sent = transport.requests_for("eth_sendTransaction");
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1][0]["to"], serde_json::json!(token_address));
+        assert_eq!(
+            decoded_calls(&transport, "burn"),
+            vec![vec![ethabi::Token::Uint(U256::from(0xabcd))]]
+        );
+        assert!(matches!(
+            lib.history()[1].kind,
+            TxKind::BurnToken { value: 0xabcd }
+        ));
+    }
+    #[tokio::test]
+    async fn long_token_uris_are_hashed() {
+        let transport = mock_chain();
+        let lib = NftPtrLibBuilder::new()
+            .run_id("test")
+            .max_token_uri_len(32)
+            .build(transport)
+            .unwrap();
+        assert_eq!(
+            lib.token_uri(0xabcd, "Cow*", &[]),
+            ("abcd%20Cow%2A&run=test".to_string(), None)
+        );
+        let long_type = "std::vector<std::map<std::string, Cow>>";
+        let full = encode_token_uri(0xabcd, long_type, &[("run", "test")]);
+        assert_eq!(
+            lib.token_uri(0xabcd, long_type, &[]),
+            (hashed_token_uri(0xabcd, &full), Some(full))
+        );
+        assert!(hashed_token_uri(0xabcd, long_type).starts_with("abcd&hash=0x"));
+    }
+    #[tokio::test]
+    async fn token_uris_have_no_run_id_unless_set() {
+        let transport = MockTransport::new();
+        let lib = NftPtrLibBuilder::new().build(transport).unwrap();
+        assert_eq!(
+            lib.token_uri(0xabcd, "Cow*", &[]),
+            ("abcd%20Cow%2A".to_string(), None)
+        );
+    }
+    #[tokio::test]
+    async fn move_token_raw_skips_demangling() {
+        let (transport, lib) = initialized_lib().await;
+        lib.move_token_raw(0x1000, 0, 0xabcd, 0, "P3Cow")
+            .await
+            .unwrap();
+        let args = &decoded_calls(&transport, "mintOrMove")[0];
+        assert_eq!(
+            args[3],
+            ethabi::Token::String("abcd%20P3Cow&run=test".to_string())
+        );
+    }
+    #[tokio::test]
+    async fn move_token_with_location_skips_symbolizing() {
+        let (transport, lib) = initialized_lib().await;
+        lib.move_token_with_location(0x1000, 0, 0xabcd, 0x42, "P3Cow", "Main.run (Main.java:7)")
+            .await
+            .unwrap();
+        let args = &decoded_calls(&transport, "mintOrMove")[0];
+        assert!(args.contains(&ethabi::Token::String("Main.run (Main.java:7)".to_string())));
+        assert!(lib.caller_locations.lock().unwrap().is_empty());
+        assert!(matches!(
+            &lib.history()[1].kind,
+            TxKind::MoveToken { caller, .. } if caller.pc == 0x42
+        ));
+    }
+    #[tokio::test]
+    async fn fire_and_forget_defers_receipts_to_flush() {
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .fire_and_forget(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        let receipts_before = transport.requests_for("eth_getTransactionReceipt").len();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        lib.move_token(0x1000, 0, 0xabce, 0, "P3Cow").await.unwrap();
+        assert_eq!(transport.requests_for("eth_sendTransaction").len(), 3);
+        assert_eq!(
+            transport.requests_for("eth_getTransactionReceipt").len(),
+            receipts_before
+        );
+        assert_eq!(lib.history().len(), 1);
+        lib.flush().await.unwrap();
+        assert_eq!(lib.history().len(), 3);
+    }
+    #[tokio::test]
+    async fn confirmed_transactions_are_not_kept() {
+        let (_, lib) = initialized_lib().await;
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        assert!(lib.submitted_transactions.lock().unwrap().is_empty());
+        assert_eq!(*lib.latest_mined_block.lock().unwrap(), Some(U64::one()));
+    }
+    #[tokio::test(start_paused = true)]
+    async fn barrier_waits_for_depth_then_forgets() {
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .fire_and_forget(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        assert_eq!(lib.submitted_transactions.lock().unwrap().len(), 1);
+        // Mined in block 1; the chain head only gets to block 2 on the second poll.
+        transport.queue_response("eth_blockNumber", serde_json::json!("0x1"));
+        transport.respond("eth_blockNumber", serde_json::json!("0x2"));
+        let polls_before = transport.requests_for("eth_blockNumber").len();
+        lib.barrier(1).await.unwrap();
+        assert!(transport.requests_for("eth_blockNumber").len() >= polls_before + 2);
+        assert!(lib.submitted_transactions.lock().unwrap().is_empty());
+        assert_eq!(*lib.latest_mined_block.lock().unwrap(), None);
+    }
+    #[tokio::test]
+    async fn shutdown_flushes_and_waits_for_everything_sent() {
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .fire_and_forget(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        assert_eq!(lib.history().len(), 1);
+        lib.shutdown(0).await.unwrap();
+        assert_eq!(lib.history().len(), 2);
+        assert!(lib.submitted_transactions.lock().unwrap().is_empty());
+        // A move that fails in the flush still gets waited for.
+        lib.move_token(0x1000, 0, 0xabce, 0, "P3Cow").await.unwrap();
+        transport.queue_error("eth_getTransactionReceipt", "header not found");
+        assert!(matches!(
+            lib.shutdown(0).await,
+            Err(NftPtrError::Web3(web3::Error::Rpc(_)))
+        ));
+        assert!(lib.submitted_transactions.lock().unwrap().is_empty());
+    }
+    #[tokio::test]
+    async fn history_keeps_only_the_most_recent_entries() {
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .history_limit(2)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        lib.move_token(0x1000, 0, 0xabce, 0, "P3Cow").await.unwrap();
+        let history = lib.history();
+        assert_eq!(history.len(), 2);
+        assert!(history
+            .iter()
+            .all(|entry| matches!(entry.kind, TxKind::MoveToken { .. })));
+    }
+    #[tokio::test]
+    async fn snapshot_and_revert() {
+        let transport = mock_chain();
+        transport.respond("evm_snapshot", serde_json::json!("0x1"));
+        transport.respond("evm_revert", serde_json::json!(true));
+        let lib = mock_lib(&transport);
+        let id = lib.snapshot().await.unwrap();
+        assert_eq!(id, U256::one());
+        assert!(lib.revert(id).await.unwrap());
+        assert_eq!(
+            transport.requests_for("evm_revert"),
+            vec![vec![serde_json::json!("0x1")]]
+        );
+    }
+    #[tokio::test]
+    async fn tx_options_follow_gas_config() {
+        let transport = MockTransport::new();
+        let lib = NftPtrLibBuilder::new()
+            .use_hardcoded_gas(true)
+            .gas_buffer_multiplier(1.5)
+            .gas_strategy(GasStrategy::Fixed(U256::from(1_000_000_000u64)))
+            .gas_price_multiplier(2.0)
+            .build(transport.clone())
+            .unwrap();
+        let options = lib.tx_options(220_000).await.unwrap();
+        assert_eq!(options.gas, Some(U256::from(330_000)));
+        assert_eq!(options.gas_price, Some(U256::from(2_000_000_000u64)));
+        assert_eq!(options.nonce, None);
+        let lib = NftPtrLibBuilder::new()
+            .use_hardcoded_gas(false)
+            .gas_strategy(GasStrategy::NodeSuggested)
+            .gas_price_multiplier(1.0)
+            .build(transport.clone())
+            .unwrap();
+        let options = lib.tx_options(220_000).await.unwrap();
+        assert_eq!(options.gas, None);
+        assert_eq!(options.gas_price, None);
+        // Left to the node, without asking it first.
+        assert!(transport.requests().is_empty());
+        let lib = NftPtrLibBuilder::new()
+            .gas_strategy(GasStrategy::Oracle(Arc::new(|| {
+                Box::pin(async { U256::from(3_000_000_000u64) })
+            })))
+            .gas_price_multiplier(1.0)
+            .build(transport.clone())
+            .unwrap();
+        let options = lib.tx_options(220_000).await.unwrap();
+        assert_eq!(options.gas_price, Some(U256::from(3_000_000_000u64)));
+    }
+    #[tokio::test]
+    async fn replay_protected_fills_in_node_signed_options() {
+        let transport = mock_chain();
+        transport.respond("eth_gasPrice", serde_json::json!("0x3b9aca00"));
+        transport.respond("eth_estimateGas", serde_json::json!("0x5208"));
+        let mut lib = test_builder()
+            .use_hardcoded_gas(false)
+            .gas_strategy(GasStrategy::NodeSuggested)
+            .gas_price_multiplier(1.0)
+            .replay_protected(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        assert_eq!(lib.chain_id, Some(1337));
+        let sent = transport.requests_for("eth_sendTransaction");
+        assert_eq!(sent[0][0]["gasPrice"], serde_json::json!("0x3b9aca00"));
+        assert_eq!(sent[0][0]["gas"], serde_json::json!("0x5208"));
+    }
+    #[tokio::test]
+    async fn local_signing_fetches_chain_id_once() {
+        let transport = mock_chain();
+        transport.respond("eth_gasPrice", serde_json::json!("0x3b9aca00"));
+        transport.respond(
+            "eth_sendRawTransaction",
+            serde_json::json!(H256::from_low_u64_be(1)),
+        );
+        let mut lib = test_builder()
+            .private_key("4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318")
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        lib.ptr_initialize(0x1000, 0, "int").await.unwrap();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "int").await.unwrap();
+        assert_eq!(lib.chain_id, Some(1337));
+        assert_eq!(transport.requests_for("eth_sendRawTransaction").len(), 3);
+        assert_eq!(transport.requests_for("eth_chainId").len(), 1);
+    }
+    #[tokio::test]
+    async fn replace_transaction_outbids_the_original() {
+        let transport = mock_chain();
+        transport.respond("eth_gasPrice", serde_json::json!("0x3b9aca00"));
+        transport.respond(
+            "eth_sendRawTransaction",
+            serde_json::json!(H256::from_low_u64_be(1)),
+        );
+        let mut lib = test_builder()
+            .private_key("4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318")
+            .fire_and_forget(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        // The move is still pending at nonce 1, priced at 2 gwei, above the current 1 gwei.
+        transport.respond(
+            "eth_getTransactionByHash",
+            serde_json::json!({
+                "hash": H256::from_low_u64_be(1),
+                "nonce": "0x1",
+                "blockHash": null,
+                "blockNumber": null,
+                "transactionIndex": null,
+                "from": lib.account,
+                "to": test_contract(),
+                "value": "0x0",
+                "gasPrice": "0x77359400",
+                "gas": "0x5208",
+                "input": "0x",
+                "v": "0x0",
+                "r": "0x0",
+                "s": "0x0",
+            }),
+        );
+        lib.replace_transaction(U256::one(), 10).await.unwrap();
+        let sent = transport.requests_for("eth_sendRawTransaction");
+        let raw = sent.last().unwrap()[0].as_str().unwrap();
+        // RLP for nonce 1, then 2.2 gwei.
+        assert!(raw.contains("018483215600"));
+    }
+    #[tokio::test]
+    async fn dedup_moves_skips_repeated_move() {
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .dedup_moves(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        let sent = || transport.requests_for("eth_sendTransaction").len();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        assert_eq!(sent(), 2);
+        // Same token to the same owner: nothing sent, but the token id is still returned.
+        assert_eq!(
+            lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap(),
+            U256::from(0xabcd)
+        );
+        assert_eq!(sent(), 2);
+        lib.move_token(0x1000, 0, 0xabce, 0, "P3Cow").await.unwrap();
+        assert_eq!(sent(), 3);
+    }
+    #[tokio::test]
+    async fn concurrent_moves_get_consecutive_nonces() {
+        let (transport, lib) = initialized_lib().await;
+        let lib = std::sync::Arc::new(lib);
+        let (first, second) = web3::futures::future::join(
+            lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow"),
+            lib.move_token(0x2000, 0, 0xabce, 0, "P3Cow"),
+        )
+        .await;
+        first.unwrap();
+        second.unwrap();
+        let mut nonces: Vec<String> = transport
+            .requests_for("eth_sendTransaction")
+            .iter()
+            .map(|params| params[0]["nonce"].as_str().unwrap().to_string())
+            .collect();
+        nonces.sort();
+        assert_eq!(nonces, vec!["0x0", "0x1", "0x2"]);
+        // Only the first send has to ask the node.
+        assert_eq!(transport.requests_for("eth_getTransactionCount").len(), 1);
+    }
+    #[tokio::test]
+    async fn worker_sends_queued_operations_in_order() {
+        let contract_address = test_contract();
+        let (_, lib) = initialized_lib().await;
+        let lib = Arc::new(lib);
+        let worker = lib.spawn_worker(1);
+        worker.ptr_initialize(0x1000, 0, "int").await.unwrap();
+        worker
+            .move_token(0x1000, 0, 0xabcd, 0, "P3Cow")
+            .await
+            .unwrap();
+        worker.flush().await.unwrap();
+        assert!(lib.is_tracked(0x1000));
+        worker.ptr_destroy(0x1000).await.unwrap();
+        worker.join().await.unwrap();
+        assert!(!lib.is_tracked(0x1000));
+        let history = lib.history();
+        assert_eq!(history.len(), 3);
+        assert!(matches!(
+            history[2].kind,
+            TxKind::MoveToken {
+                owner_contract,
+                ..
+            } if owner_contract == contract_address
+        ));
+    }
+    #[tokio::test]
+    async fn worker_reports_failures_on_flush() {
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .owner_fallback(OwnerFallback::ErrorOnUnknown)
+            .build(transport)
+            .unwrap();
+        lib.initialize().await.unwrap();
+        let worker = Arc::new(lib).spawn_worker(4);
+        // Queueing succeeds; the move fails in the background.
+        worker
+            .move_token(0x2000, 0, 0xabcd, 0, "P3Cow")
+            .await
+            .unwrap();
+        assert!(matches!(
+            worker.flush().await,
+            Err(NftPtrError::UntrackedPointer(0x2000))
+        ));
+        worker.join().await.unwrap();
+    }
+    #[tokio::test]
+    async fn resync_nonce_picks_up_node_count() {
+        let (transport, lib) = initialized_lib().await;
+        // Something else sends four transactions from our account.
+        transport.respond("eth_getTransactionCount", serde_json::json!("0x5"));
+        assert_eq!(lib.resync_nonce().await.unwrap(), U256::from(5));
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        let sent = transport.requests_for("eth_sendTransaction");
+        assert_eq!(sent[1][0]["nonce"], serde_json::json!("0x5"));
+    }
+    #[tokio::test]
+    async fn access_lists_attached_to_moves() {
+        let token_address = test_contract();
+        let transport = mock_chain();
+        let access_list = serde_json::json!([{
+            "address": token_address,
+            "storageKeys": [H256::from_low_u64_be(7)],
+        }]);
+        transport.respond(
+            "eth_createAccessList",
+            serde_json::json!({"accessList": access_list, "gasUsed": "0x5208"}),
+        );
+        let mut lib = test_builder()
+            .access_lists(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        let sent = transport.requests_for("eth_sendTransaction");
+        // Not for the deploy, which has no `to`.
+        assert_eq!(transport.requests_for("eth_createAccessList").len(), 1);
+        assert!(sent[0][0].get("accessList").is_none());
+        assert_eq!(sent[1][0]["accessList"], access_list);
+        assert_eq!(sent[1][0]["type"], serde_json::json!("0x1"));
+    }
+    #[tokio::test]
+    async fn access_lists_skipped_without_node_support() {
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .access_lists(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        let sent = transport.requests_for("eth_sendTransaction");
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1][0].get("accessList").is_none());
+    }
+    #[tokio::test]
+    async fn nonce_error_resyncs_only_when_enabled() {
+        let (transport, lib) = initialized_lib().await;
+        transport.respond("eth_getTransactionCount", serde_json::json!("0x5"));
+        transport.queue_error("eth_sendTransaction", "nonce too low");
+        assert!(lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.is_err());
+        // Not sent again, since the node may already have the first one.
+        assert_eq!(transport.requests_for("eth_sendTransaction").len(), 2);
+
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .resync_nonce_on_error(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        transport.respond("eth_getTransactionCount", serde_json::json!("0x5"));
+        transport.queue_error("eth_sendTransaction", "nonce too low");
+        lib.move_token(0x1000, 0, 0xabcd, 0, "P3Cow").await.unwrap();
+        let sent = transport.requests_for("eth_sendTransaction");
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[1][0]["nonce"], serde_json::json!("0x1"));
+        assert_eq!(sent[2][0]["nonce"], serde_json::json!("0x5"));
+        // And the count carries on from there.
+        lib.move_token(0x1000, 0, 0xabce, 0, "P3Cow").await.unwrap();
+        let sent = transport.requests_for("eth_sendTransaction");
+        assert_eq!(sent[3][0]["nonce"], serde_json::json!("0x6"));
+    }
+    #[tokio::test]
+    async fn token_exists_checks_owner_of() {
+        let account = test_account();
+        let (transport, lib) = initialized_lib().await;
+        // With no eth_call response, the mock answers with an RPC error like a revert.
+        assert!(!lib.token_exists(0xabcd).await.unwrap());
+        transport.respond(
+            "eth_call",
+            serde_json::json!(format!(
+                "0x{}",
+                hex::encode(ethabi::encode(&[ethabi::Token::Address(account)]))
+            )),
+        );
+        assert!(lib.token_exists(0xabcd).await.unwrap());
+    }
+    #[tokio::test]
+    async fn transaction_status_reads_receipt() {
+        let transport = mock_chain();
+        let lib = mock_lib(&transport);
+        assert_eq!(
+            lib.transaction_status(H256::from_low_u64_be(1))
+                .await
+                .unwrap(),
+            TxStatus::Mined {
+                block: U64::one(),
+                success: true
+            }
+        );
+        transport.respond("eth_getTransactionReceipt", serde_json::Value::Null);
+        transport.respond("eth_getTransactionByHash", serde_json::Value::Null);
+        assert_eq!(
+            lib.transaction_status(H256::from_low_u64_be(1))
+                .await
+                .unwrap(),
+            TxStatus::Dropped
+        );
+    }
+    #[tokio::test]
+    async fn initialize_uses_account_index() {
+        let transport = mock_chain();
+        let second_account = Address::from_low_u64_be(0x4321);
+        transport.respond(
+            "eth_accounts",
+            serde_json::json!([Address::from_low_u64_be(0x1234), second_account]),
+        );
+        let mut lib = NftPtrLibBuilder::new()
+            .num_confirmations(0)
+            .account_index(1)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        assert_eq!(lib.account, second_account);
+        let mut lib = NftPtrLibBuilder::new()
+            .account_index(2)
+            .build(transport.clone())
+            .unwrap();
+        assert!(matches!(
+            lib.initialize().await,
+            Err(NftPtrError::NoAccounts {
+                account_index: 2,
+                num_accounts: 2
+            })
+        ));
+        transport.respond("eth_accounts", serde_json::json!([]));
+        let mut lib = mock_lib(&transport);
+        let err = lib.initialize().await.unwrap_err();
+        assert!(matches!(
+            err,
+            NftPtrError::NoAccounts {
+                account_index: 0,
+                num_accounts: 0
+            }
+        ));
+        assert!(err.to_string().contains("NFT_PTR_PRIVATE_KEY"));
+    }
+    #[tokio::test]
+    async fn load_state_resumes_saved_contracts() {
+        let token_address = test_contract();
+        let transport = mock_chain();
+        let mut lib = mock_lib(&transport);
+        lib.initialize().await.unwrap();
+        lib.ptr_initialize(0x1000, 0, "P3Cow").await.unwrap();
+        let path = std::env::temp_dir().join(format!("nft_ptr_state_{}.json", std::process::id()));
+        lib.save_state(&path).unwrap();
+
+        let transport = mock_chain();
+        let mut lib = mock_lib(&transport);
+        lib.load_state(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        lib.initialize().await.unwrap();
+        assert!(transport.requests_for("eth_sendTransaction").is_empty());
+        assert_eq!(lib.token_contracts[0].address(), token_address);
+        assert_eq!(
+            lib.owner_contract_of(0x1000).unwrap(),
+            OwnerContract::Tracked(token_address)
+        );
+    }
+    #[tokio::test]
+    async fn config_reports_effective_settings() {
+        let account = test_account();
+        let transport = mock_chain();
+        let mut lib = mock_lib(&transport);
+        let config = lib.config();
+        assert_eq!(
+            config.deploy_confirmation_policy,
+            ConfirmationPolicy::Blocks(0)
+        );
+        assert_eq!(
+            config.call_confirmation_policy,
+            ConfirmationPolicy::Blocks(0)
+        );
+        assert!(!config.dry_run);
+        assert_eq!(config.network_id, 0);
+        lib.set_num_confirmations(2);
+        assert_eq!(
+            lib.config().call_confirmation_policy,
+            ConfirmationPolicy::Blocks(2)
+        );
+        lib.initialize().await.unwrap();
+        let config = lib.config();
+        assert_eq!(config.network_id, 1337);
+        assert_eq!(config.account, account);
+        assert!(!config.signs_locally);
+    }
+    #[tokio::test]
+    async fn resolve_owner_tells_unknown_from_default_account() {
+        let account = test_account();
+        let contract_address = test_contract();
+        let (_, lib) = initialized_lib().await;
+        lib.ptr_initialize(0x1000, 0, "int").await.unwrap();
+        assert_eq!(
+            lib.resolve_owner(0x1000),
+            OwnerResolution::Tracked(contract_address)
+        );
+        assert_eq!(
+            lib.resolve_owner(0),
+            OwnerResolution::DefaultAccount(account)
+        );
+        assert_eq!(lib.resolve_owner(0x2000), OwnerResolution::Unknown);
+        assert_eq!(
+            lib.owner_contract_of(0x2000).unwrap(),
+            OwnerContract::Fallback(account)
+        );
+        // move_token still sends tokens for unknown owners to our account.
+        assert_eq!(
+            lib.mem_address_to_owner_contract_address(0x2000).unwrap(),
+            account
+        );
+    }
+    #[tokio::test]
+    async fn owner_fallback_policies() {
+        let account = test_account();
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .owner_fallback(OwnerFallback::ErrorOnUnknown)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        assert!(matches!(
+            lib.move_token(0x2000, 0, 0xabcd, 0, "P3Cow").await,
+            Err(NftPtrError::UntrackedPointer(0x2000))
+        ));
+        assert!(matches!(
+            lib.owner_contract_of(0x2000),
+            Err(NftPtrError::UntrackedPointer(0x2000))
+        ));
+        // Address 0 isn't unknown, so minting to our account still works.
+        assert_eq!(
+            lib.mem_address_to_owner_contract_address(0).unwrap(),
+            account
+        );
+        let lib = NftPtrLibBuilder::new()
+            .owner_fallback(OwnerFallback::FallbackToZero)
+            .build(transport)
+            .unwrap();
+        assert_eq!(
+            lib.mem_address_to_owner_contract_address(0x2000).unwrap(),
+            Address::zero()
+        );
+    }
+    #[tokio::test]
+    async fn force_allow_fork_runs_on_local_fork() {
+        let transport = MockTransport::with_chain(
+            1,
+            Address::from_low_u64_be(0x1234),
+            Address::from_low_u64_be(0x5678),
+        );
+        transport.respond("web3_clientVersion", serde_json::json!("anvil/v0.1.0"));
+        let mut lib = test_builder()
+            .force_allow_fork(true)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        assert_eq!(lib.network_id, 1);
+        assert!(!is_dev_node("Geth/v1.10.8-stable/linux-amd64/go1.16.6"));
+    }
+    #[tokio::test]
+    async fn initialize_refuses_mainnet() {
+        let transport = MockTransport::with_chain(1, test_account(), test_contract());
+        transport.respond(
+            "web3_clientVersion",
+            serde_json::json!("Geth/v1.10.8-stable/linux-amd64/go1.16.6"),
+        );
+        let mut lib = mock_lib(&transport);
+        assert!(matches!(
+            lib.initialize().await,
+            Err(NftPtrError::MainnetRefused {
+                client_version: None
+            })
+        ));
+        let mut lib = test_builder()
+            .force_allow_fork(true)
+            .build(transport.clone())
+            .unwrap();
+        assert!(matches!(
+            lib.initialize().await,
+            Err(NftPtrError::MainnetRefused {
+                client_version: Some(_)
+            })
+        ));
+        assert!(transport.requests_for("eth_sendTransaction").is_empty());
+    }
+    #[tokio::test]
+    async fn close_closes_transport() {
+        let (transport, lib) = initialized_lib().await;
+        lib.close().await.unwrap();
+        let requests = transport.requests().len();
+        assert!(web3::Web3::new(transport.clone())
+            .eth()
+            .block_number()
+            .await
+            .is_err());
+        assert_eq!(transport.requests().len(), requests);
+    }
+    #[tokio::test]
+    async fn health_check_reports_bad_net_version() {
+        let transport = mock_chain();
+        transport.respond("eth_syncing", serde_json::json!(false));
+        let lib = mock_lib(&transport);
+        assert_eq!(lib.health_check().await.unwrap().network_id, 1337);
+        transport.respond("net_version", serde_json::json!("<html>"));
+        assert!(matches!(
+            lib.health_check().await,
+            Err(NftPtrError::Web3(web3::Error::Decoder(_)))
+        ));
+    }
+    #[test]
+    fn bad_allowed_network_ids_are_an_error() {
+        assert_eq!(
+            builder::parse_network_ids("5, 1337").unwrap(),
+            [5, 1337]
+                .iter()
+                .copied()
+                .collect::<std::collections::HashSet<u32>>()
+        );
+        assert!(matches!(
+            builder::parse_network_ids("5,goerli"),
+            Err(NftPtrError::BadEnvVar { .. })
+        ));
+    }
+    #[tokio::test]
+    async fn initialize_refuses_disallowed_network() {
+        let transport = mock_chain();
+        let mut lib = NftPtrLibBuilder::new()
+            .allowed_network_ids(vec![5])
+            .build(transport.clone())
+            .unwrap();
+        assert!(matches!(
+            lib.initialize().await,
+            Err(NftPtrError::DisallowedNetwork(1337))
+        ));
+        assert!(transport.requests_for("eth_sendTransaction").is_empty());
+    }
+    #[tokio::test]
+    async fn ptr_destroy_reports_untracked() {
+        let contract_address = test_contract();
+        let (_, lib) = initialized_lib().await;
+        let token = lib.ptr_initialize(0x1000, 0, "int").await.unwrap();
+        assert_eq!(token.owner_address, 0x1000);
+        assert_eq!(token.contract, contract_address);
+        assert!(lib.is_tracked(0x1000));
+        assert!(lib.ptr_destroy_token(token).await.unwrap());
+        assert!(!lib.is_tracked(0x1000));
+        assert!(!lib.ptr_destroy(0x1000).await.unwrap());
+        assert!(!lib.ptr_destroy(0x2000).await.unwrap());
+    }
+    #[tokio::test]
+    async fn reinitialized_ptr_stacks_owner_contracts() {
+        let contract_address = test_contract();
+        let (_, lib) = initialized_lib().await;
+        lib.ptr_initialize(0x1000, 0, "int").await.unwrap();
+        lib.ptr_initialize(0x1000, 0, "int").await.unwrap();
+        // The first destroy only pops the second owner contract.
+        assert!(lib.ptr_destroy(0x1000).await.unwrap());
+        assert_eq!(
+            lib.resolve_owner(0x1000),
+            OwnerResolution::Tracked(contract_address)
+        );
+        assert!(lib.ptr_destroy(0x1000).await.unwrap());
+        assert!(!lib.is_tracked(0x1000));
+        assert!(!lib.ptr_destroy(0x1000).await.unwrap());
+    }
+    #[tokio::test]
+    async fn explorer_urls_only_on_known_networks() {
+        let account = test_account();
+        let contract_address = test_contract();
+        let transport = MockTransport::with_chain(5, account, contract_address);
+        let mut lib = mock_lib(&transport);
+        lib.initialize().await.unwrap();
+        assert_eq!(
+            lib.etherscan_address_url(account).unwrap(),
+            format!("https://goerli.etherscan.io/address/{:#x}", account)
+        );
+        assert_eq!(
+            lib.etherscan_token_url(contract_address).unwrap(),
+            format!("https://goerli.etherscan.io/token/{:#x}", contract_address)
+        );
+        assert_eq!(
+            lib.opensea_asset_url(0xabcd).unwrap(),
+            format!(
+                "https://testnets.opensea.io/assets/goerli/{:#x}/0xabcd",
+                contract_address
+            )
+        );
+        let (_, lib) = initialized_lib().await;
+        assert_eq!(lib.etherscan_address_url(account), None);
+        assert_eq!(lib.opensea_asset_url(0xabcd), None);
+    }
+    #[tokio::test]
+    async fn owner_contracts_sorted_by_address() {
+        let contract_address = test_contract();
+        let (_, lib) = initialized_lib().await;
+        assert!(lib.owner_contracts().is_empty());
+        for owner_address in &[0x3000, 0x1000, 0x2000] {
+            lib.ptr_initialize(*owner_address, 0, "int").await.unwrap();
+        }
+        lib.ptr_destroy(0x2000).await.unwrap();
+        assert_eq!(
+            lib.owner_contracts(),
+            vec![(0x1000, contract_address), (0x3000, contract_address)]
+        );
+    }
+    #[tokio::test]
+    async fn ptr_initialize_many_tracks_every_ptr() {
+        let account = test_account();
+        let contract_address = test_contract();
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .init_concurrency(2)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        // The first deploy fails; the others go ahead anyway.
+        transport.queue_error("eth_sendTransaction", "insufficient funds for gas");
+        let requests = (1..=5)
+            .map(|i| PtrInitRequest {
+                owner_address: 0x1000 * i,
+                caller_pc: 0,
+                ptr_object_type: "int".to_string(),
+            })
+            .collect();
+        let tokens = lib.ptr_initialize_many(requests).await;
+        assert_eq!(tokens.len(), 5);
+        assert!(tokens[0].is_err());
+        assert!(tokens[1..].iter().all(Result::is_ok));
+        assert_eq!(
+            lib.owner_contract_of(0x1000).unwrap(),
+            OwnerContract::Fallback(account)
+        );
+        for i in 2..=5 {
+            assert_eq!(
+                lib.owner_contract_of(0x1000 * i).unwrap(),
+                OwnerContract::Tracked(contract_address)
+            );
+        }
+        // The token contract plus one owner contract per nft_ptr, failed or not.
+        assert_eq!(transport.requests_for("eth_sendTransaction").len(), 6);
+    }
+    #[tokio::test]
+    async fn audit_log_records_every_operation() {
+        let transport = mock_chain();
+        let path = std::env::temp_dir().join(format!("nft_ptr_audit_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut lib = test_builder()
+            .audit_log_path(&path)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        lib.ptr_initialize(0x1000, 0, "int").await.unwrap();
+        lib.move_token(0x1000, 0, 0xabcd, 0, "int").await.unwrap();
+        lib.ptr_destroy(0x1000).await.unwrap();
+        let types: Vec<String> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record["kind"]["type"].as_str().unwrap().to_string()
+            })
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            types,
+            vec!["DeployToken", "PtrInitialize", "MoveToken", "PtrDestroy"]
+        );
+    }
+    #[tokio::test]
+    async fn initialize_deploys_token_shards() {
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .token_shards(3)
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        assert_eq!(lib.token_contracts.len(), 3);
+        assert_eq!(transport.requests_for("eth_sendTransaction").len(), 3);
+        lib.move_token(0x1000, 0, 0xabcd, 0, "int").await.unwrap();
+        assert_eq!(transport.requests_for("eth_sendTransaction").len(), 4);
+    }
+    #[tokio::test]
+    async fn token_calls_before_initialize_are_errors() {
+        let transport = mock_chain();
+        let lib = mock_lib(&transport);
+        assert!(matches!(
+            lib.owner_of(0xabcd).await,
+            Err(NftPtrError::NotInitialized)
+        ));
+        assert!(matches!(
+            lib.tokens_of_owner(0).await,
+            Err(NftPtrError::NotInitialized)
+        ));
+        assert!(matches!(
+            lib.set_approval_for_all(test_contract(), true).await,
+            Err(NftPtrError::NotInitialized)
+        ));
+        assert_eq!(lib.opensea_asset_url(0xabcd), None);
+    }
+    #[tokio::test]
+    async fn initialize_reports_reverted_deploy() {
+        let transport = mock_chain();
+        transport.queue_response(
+            "eth_getTransactionReceipt",
+            serde_json::json!({
+                "transactionHash": H256::from_low_u64_be(1),
+                "transactionIndex": "0x0",
+                "blockHash": H256::from_low_u64_be(2),
+                "blockNumber": "0x1",
+                "from": test_account(),
+                "to": null,
+                "cumulativeGasUsed": "0x5208",
+                "gasUsed": "0x5208",
+                "contractAddress": null,
+                "logs": [],
+                "status": "0x0",
+                "logsBloom": web3::types::H2048::zero(),
+            }),
+        );
+        let mut lib = mock_lib(&transport);
+        assert!(matches!(
+            lib.initialize().await,
+            Err(NftPtrError::DeployReverted {
+                gas_used: Some(gas_used),
+                ..
+            }) if gas_used == U256::from(21_000)
+        ));
+    }
+    #[tokio::test(start_paused = true)]
+    async fn initialize_fails_without_token_code() {
+        let transport = mock_chain();
+        transport.respond("eth_getCode", serde_json::json!("0x"));
+        let mut lib = mock_lib(&transport);
+        assert!(matches!(
+            lib.initialize().await,
+            Err(NftPtrError::ContractDeploy(_))
+        ));
+        assert_eq!(
+            transport.requests_for("eth_getCode").len(),
+            CODE_CHECK_ATTEMPTS
+        );
+    }
+    #[tokio::test]
+    async fn initialize_mints_preset_tokens() {
+        let token_address = test_contract();
+        let transport = mock_chain();
+        let mut lib = test_builder()
+            .preset_tokens(vec![(1, "int".to_string()), (2, "P3Cow".to_string())])
+            .build(transport.clone())
+            .unwrap();
+        lib.initialize().await.unwrap();
+        let sent = transport.requests_for("eth_sendTransaction");
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[1][0]["to"], serde_json::json!(token_address));
+        assert_eq!(sent[2][0]["to"], serde_json::json!(token_address));
+    }
+    #[test]
+    fn derive_key_from_mnemonic() {
+        // Hardhat's and Anvil's default mnemonic, and the key they print for account 0.
+        let mnemonic = "test test test test test test test test test test test junk";
+        assert_eq!(
+            derive_key(mnemonic, DEFAULT_DERIVATION_PATH).unwrap(),
+            builder::parse_private_key(
+                "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80"
+            )
+            .unwrap()
+        );
+        assert!(matches!(
+            derive_key("test test test", DEFAULT_DERIVATION_PATH),
+            Err(NftPtrError::Keystore(_))
+        ));
+        assert!(matches!(
+            derive_key(mnemonic, "44/60/0/0/0"),
+            Err(NftPtrError::Keystore(_))
+        ));
+    }
+    #[test]
+    fn token_contract_name_pinned() {
+        let lib = NftPtrLibBuilder::new()
+            .program_name("example")
+            .clock(|| 1617933468000)
+            .build(web3::transports::Http::new("http://127.0.0.1:7545").unwrap())
+            .unwrap();
+        assert_eq!(
+            lib.token_contract_name(),
+            "NftPtrToken example 1617933468000"
+        );
+    }
+    #[test]
+    fn token_contract_name_overrides() {
+        let transport = MockTransport::new();
+        let lib = NftPtrLibBuilder::new()
+            .deterministic_name(true)
+            .build(transport.clone())
+            .unwrap();
+        assert_eq!(lib.token_contract_name(), "NftPtrToken");
+        let lib = NftPtrLibBuilder::new()
+            .deterministic_name(true)
+            .token_name("My pointers")
+            .build(transport)
+            .unwrap();
+        assert_eq!(lib.token_contract_name(), "My pointers");
     }
 }
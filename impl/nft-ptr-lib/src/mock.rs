@@ -0,0 +1,168 @@
+// A web3 transport that answers from canned responses instead of talking to a node, and
+// records every request it gets, so NftPtrLib can be tested without Ganache.
+
+use crate::CloseTransport;
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use web3::futures::future::{self, Ready};
+use web3::types::{Address, H256};
+use web3::{helpers, rpc, RequestId, Transport};
+
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    next_id: RequestId,
+    // Answers for every request of a method...
+    responses: HashMap<String, rpc::Value>,
+    // ...except these one-off answers, which are used up first.
+    queued: HashMap<String, VecDeque<Result<rpc::Value, rpc::Error>>>,
+    requests: Vec<(String, Vec<rpc::Value>)>,
+    closed: bool,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    // Answers like a Ganache-style dev node on `network_id` with one unlocked `account`:
+    // every transaction is mined straight away, successfully, and a deploy lands at
+    // `contract_address`.
+    pub fn with_chain(
+        network_id: u32,
+        account: Address,
+        contract_address: Address,
+    ) -> MockTransport {
+        let transport = MockTransport::new();
+        let transaction_hash = H256::from_low_u64_be(1);
+        transport.respond("eth_accounts", json!([account]));
+        transport.respond("net_version", json!(network_id.to_string()));
+        transport.respond("eth_chainId", json!(format!("{:#x}", network_id)));
+        transport.respond("eth_getBalance", json!("0xde0b6b3a7640000"));
+        transport.respond("eth_blockNumber", json!("0x1"));
+        transport.respond("eth_getTransactionCount", json!("0x0"));
+        transport.respond("eth_sendTransaction", json!(transaction_hash));
+        transport.respond("eth_getCode", json!("0x6080"));
+        transport.respond(
+            "eth_getTransactionReceipt",
+            json!({
+                "transactionHash": transaction_hash,
+                "transactionIndex": "0x0",
+                "blockHash": H256::from_low_u64_be(2),
+                "blockNumber": "0x1",
+                "from": account,
+                "to": null,
+                "cumulativeGasUsed": "0x5208",
+                "gasUsed": "0x5208",
+                "contractAddress": contract_address,
+                "logs": [],
+                "status": "0x1",
+                "logsBloom": web3::types::H2048::zero(),
+            }),
+        );
+        transport
+    }
+
+    // Answers every `method` request with `response` from now on.
+    pub fn respond(&self, method: &str, response: rpc::Value) {
+        let mut state = self.state.lock().unwrap();
+        state.responses.insert(method.to_string(), response);
+    }
+
+    // Answers the next `method` request with `response`, e.g. a pending receipt before the
+    // mined one. Queued responses are used in order before falling back to `respond`'s.
+    pub fn queue_response(&self, method: &str, response: rpc::Value) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .queued
+            .entry(method.to_string())
+            .or_default()
+            .push_back(Ok(response));
+    }
+
+    // Like queue_response, but the next `method` request fails with `message`, the way a
+    // node refuses a transaction.
+    pub fn queue_error(&self, method: &str, message: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .queued
+            .entry(method.to_string())
+            .or_default()
+            .push_back(Err(rpc::Error {
+                code: rpc::ErrorCode::ServerError(-32000),
+                message: message.to_string(),
+                data: None,
+            }));
+    }
+
+    // Every (method, params) sent so far, in order.
+    pub fn requests(&self) -> Vec<(String, Vec<rpc::Value>)> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    // The params of every `method` request sent so far.
+    pub fn requests_for(&self, method: &str) -> Vec<Vec<rpc::Value>> {
+        self.requests()
+            .into_iter()
+            .filter(|(sent, _)| sent == method)
+            .map(|(_, params)| params)
+            .collect()
+    }
+}
+
+impl CloseTransport for MockTransport {
+    // Requests after this fail, like they would on a closed socket.
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+    }
+}
+
+impl Transport for MockTransport {
+    type Out = Ready<web3::Result<rpc::Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        (
+            state.next_id,
+            helpers::build_request(state.next_id, method, params),
+        )
+    }
+
+    fn send(&self, _id: RequestId, request: rpc::Call) -> Self::Out {
+        let (method, params) = match request {
+            rpc::Call::MethodCall(call) => (
+                call.method,
+                match call.params {
+                    rpc::Params::Array(params) => params,
+                    rpc::Params::Map(map) => vec![rpc::Value::Object(map)],
+                    rpc::Params::None => Vec::new(),
+                },
+            ),
+            _ => return future::ready(Err(web3::Error::Unreachable)),
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return future::ready(Err(web3::Error::Unreachable));
+        }
+        state.requests.push((method.clone(), params));
+        let response = match state.queued.get_mut(&method).and_then(VecDeque::pop_front) {
+            Some(response) => response,
+            None => state
+                .responses
+                .get(&method)
+                .cloned()
+                .ok_or_else(|| rpc::Error {
+                    code: rpc::ErrorCode::MethodNotFound,
+                    message: format!("MockTransport has no response for {}", method),
+                    data: None,
+                }),
+        };
+        future::ready(response.map_err(web3::Error::Rpc))
+    }
+}
@@ -0,0 +1,45 @@
+use crate::NftPtrError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use web3::types::Address;
+
+// What NftPtrLib::save_state writes: enough for a later run to pick up tracking where
+// this one left off.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SavedState {
+    pub(crate) token_contract: Address,
+    // The rest of the token shards, if there's more than one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) extra_token_contracts: Vec<Address>,
+    // nft_ptr address (in hex, since JSON keys have to be strings) -> its owner contract.
+    pub(crate) nft_ptrs: BTreeMap<String, Address>,
+    // For nft_ptrs initialized more than once: the older owner contracts under the one in
+    // nft_ptrs, oldest first.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) shadowed_nft_ptrs: BTreeMap<String, Vec<Address>>,
+}
+
+impl SavedState {
+    pub(crate) fn read(path: &Path) -> Result<SavedState, NftPtrError> {
+        let json = std::fs::read(path)
+            .map_err(|err| NftPtrError::State(format!("can't read {}: {}", path.display(), err)))?;
+        serde_json::from_slice(&json)
+            .map_err(|err| NftPtrError::State(format!("can't parse {}: {}", path.display(), err)))
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<(), NftPtrError> {
+        let json = serde_json::to_vec_pretty(self).unwrap();
+        std::fs::write(path, json)
+            .map_err(|err| NftPtrError::State(format!("can't write {}: {}", path.display(), err)))
+    }
+}
+
+pub(crate) fn format_ptr_address(owner_address: u64) -> String {
+    format!("{:#x}", owner_address)
+}
+
+pub(crate) fn parse_ptr_address(key: &str) -> Result<u64, NftPtrError> {
+    u64::from_str_radix(key.trim_start_matches("0x"), 16)
+        .map_err(|_| NftPtrError::State(format!("bad nft_ptr address {:?}", key)))
+}
@@ -0,0 +1,98 @@
+// Resolving caller PCs against an object file's debug info with addr2line, for PCs that
+// weren't captured by this process (another binary, or a core dump).
+
+use crate::{demangle_cpp, CallerLocation, DemangleMode, NftPtrError};
+use addr2line::gimli::{self, EndianArcSlice, RunTimeEndian};
+use addr2line::object::{Object, ObjectSection};
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Where caller PCs get their function, file and line from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Symbolizer {
+    // backtrace::resolve against the modules loaded in this process.
+    InProcess,
+    // The debug info in the object file at `path`, which was loaded at `base_address` in
+    // the process the PCs came from (0 for a non-PIE executable).
+    ObjectFile { path: PathBuf, base_address: u64 },
+}
+
+impl Default for Symbolizer {
+    fn default() -> Symbolizer {
+        Symbolizer::InProcess
+    }
+}
+
+// An object file's parsed debug info, parsed once when the lib is built and kept for its
+// lifetime. The sections are Arc-backed so it can move between threads; addr2line fills in
+// line tables lazily, so the lib keeps it behind a Mutex.
+pub(crate) struct ObjectContext {
+    context: addr2line::Context<EndianArcSlice<RunTimeEndian>>,
+    base_address: u64,
+}
+
+impl ObjectContext {
+    pub(crate) fn load(path: &Path, base_address: u64) -> Result<ObjectContext, NftPtrError> {
+        let error = |msg: String| NftPtrError::Symbolizer(format!("{}: {}", path.display(), msg));
+        let data = std::fs::read(path).map_err(|err| error(err.to_string()))?;
+        let object =
+            addr2line::object::File::parse(&*data).map_err(|err| error(err.to_string()))?;
+        let endian = if object.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        // A missing section is just empty, like addr2line::Context::new treats it.
+        let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+            let section = object
+                .section_by_name(id.name())
+                .and_then(|section| section.uncompressed_data().ok())
+                .unwrap_or(Cow::Borrowed(&[]));
+            Ok(EndianArcSlice::new(Arc::from(&*section), endian))
+        })
+        .map_err(|err| error(err.to_string()))?;
+        let context =
+            addr2line::Context::from_dwarf(dwarf).map_err(|err| error(err.to_string()))?;
+        Ok(ObjectContext {
+            context,
+            base_address,
+        })
+    }
+
+    // Like the in-process resolution: the innermost frame that has a name. A PC outside the
+    // object file, or without debug info, resolves to just the PC.
+    pub(crate) fn resolve(&self, pc: u64, mode: DemangleMode) -> CallerLocation {
+        let mut location = CallerLocation {
+            pc,
+            symbol: None,
+            file: None,
+            line: None,
+        };
+        let probe = match pc.checked_sub(self.base_address) {
+            Some(probe) => probe,
+            None => return location,
+        };
+        let mut frames = match self.context.find_frames(probe) {
+            Ok(frames) => frames,
+            Err(_) => return location,
+        };
+        while let Ok(Some(frame)) = frames.next() {
+            let name = match frame
+                .function
+                .as_ref()
+                .and_then(|name| name.raw_name().ok())
+            {
+                Some(name) => name,
+                None => continue,
+            };
+            location.symbol = Some(demangle_cpp(&name, mode).into_owned());
+            if let Some(source) = frame.location {
+                location.file = source.file.map(str::to_string);
+                location.line = source.line;
+            }
+            break;
+        }
+        location
+    }
+}
@@ -0,0 +1,151 @@
+// IPC transport that reopens its socket when the node goes away (e.g. Geth restarting),
+// so a brief blip doesn't permanently break every following move_token.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+use web3::futures::future::BoxFuture;
+use web3::transports::{Either, Http, Ipc};
+use web3::{helpers, rpc, RequestId, Transport};
+
+const MAX_RECONNECT_ATTEMPTS: u32 = 3;
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub struct ReconnectingIpc {
+    path: PathBuf,
+    // None once closed.
+    inner: Arc<Mutex<Option<Ipc>>>,
+    id: Arc<AtomicUsize>,
+}
+
+impl ReconnectingIpc {
+    pub async fn new(path: impl Into<PathBuf>) -> web3::Result<ReconnectingIpc> {
+        let path = path.into();
+        let ipc = Ipc::new(&path).await?;
+        Ok(ReconnectingIpc {
+            path,
+            inner: Arc::new(Mutex::new(Some(ipc))),
+            id: Arc::new(AtomicUsize::new(1)),
+        })
+    }
+
+    async fn reconnect(&self) -> web3::Result<()> {
+        let ipc = Ipc::new(&self.path).await?;
+        let mut inner = self.inner.lock().unwrap();
+        // Don't reopen a socket that was closed while we were reconnecting.
+        if inner.is_some() {
+            *inner = Some(ipc);
+        }
+        Ok(())
+    }
+}
+
+// Errors that mean the socket itself is gone, as opposed to the node rejecting the request.
+fn is_disconnect(err: &web3::Error) -> bool {
+    matches!(err, web3::Error::Transport(_) | web3::Error::Io(_))
+}
+
+// Whether `request` is safe to send again after a disconnect. A transaction may have reached
+// the node before the socket went, and sending it twice could spend the nonce or the gas twice.
+fn is_retryable(request: &rpc::Call) -> bool {
+    match request {
+        rpc::Call::MethodCall(call) => !matches!(
+            call.method.as_str(),
+            "eth_sendTransaction" | "eth_sendRawTransaction" | "personal_sendTransaction"
+        ),
+        _ => true,
+    }
+}
+
+impl Transport for ReconnectingIpc {
+    type Out = BoxFuture<'static, web3::Result<rpc::Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        let id = self.id.fetch_add(1, Ordering::AcqRel);
+        (id, helpers::build_request(id, method, params))
+    }
+
+    fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+        let this = self.clone();
+        Box::pin(async move {
+            let mut attempts = 0;
+            loop {
+                let ipc = match this.inner.lock().unwrap().clone() {
+                    Some(ipc) => ipc,
+                    None => {
+                        return Err(web3::Error::Io(std::io::Error::new(
+                            std::io::ErrorKind::NotConnected,
+                            "IPC transport was closed",
+                        )))
+                    }
+                };
+                match ipc.send(id, request.clone()).await {
+                    Err(err) if is_disconnect(&err) && !is_retryable(&request) => {
+                        warn!(
+                            "IPC transport error ({}) while sending a transaction, reconnecting \
+                             to {} without resending it",
+                            err,
+                            this.path.display()
+                        );
+                        if let Err(err) = this.reconnect().await {
+                            warn!("Failed to reconnect to {}: {}", this.path.display(), err);
+                        }
+                        return Err(err);
+                    }
+                    Err(err) if is_disconnect(&err) && attempts < MAX_RECONNECT_ATTEMPTS => {
+                        attempts += 1;
+                        warn!(
+                            "IPC transport error ({}), reconnecting to {} (attempt {}/{})",
+                            err,
+                            this.path.display(),
+                            attempts,
+                            MAX_RECONNECT_ATTEMPTS
+                        );
+                        tokio::time::sleep(RECONNECT_DELAY * attempts).await;
+                        if let Err(err) = this.reconnect().await {
+                            warn!("Failed to reconnect to {}: {}", this.path.display(), err);
+                        }
+                    }
+                    result => return result,
+                }
+            }
+        })
+    }
+}
+
+// A transport that can hang up on the node when asked, instead of whenever its last clone
+// happens to be dropped; see NftPtrLib::close.
+pub trait CloseTransport: Transport {
+    fn close(&self);
+}
+
+// Nothing to close: every request is its own HTTP request.
+impl CloseTransport for Http {
+    fn close(&self) {}
+}
+
+// The socket closes as soon as requests already in flight are done; later ones fail
+// without reconnecting.
+impl CloseTransport for ReconnectingIpc {
+    fn close(&self) {
+        self.inner.lock().unwrap().take();
+    }
+}
+
+impl<A, B> CloseTransport for Either<A, B>
+where
+    A: CloseTransport,
+    B: CloseTransport,
+    A::Out: 'static + Send,
+    B::Out: 'static + Send,
+{
+    fn close(&self) {
+        match self {
+            Either::Left(transport) => transport.close(),
+            Either::Right(transport) => transport.close(),
+        }
+    }
+}
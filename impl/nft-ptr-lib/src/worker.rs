@@ -0,0 +1,183 @@
+// A background task that sends the transactions for an Arc<NftPtrLib>, so an instrumented
+// program only ever waits for room in a queue, not for the chain.
+
+use crate::{NftPtrError, NftPtrLib};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+enum WorkerOp {
+    MoveToken {
+        owner_address: u64,
+        previous_owner_address: u64,
+        value: u64,
+        caller_pc: u64,
+        object_type: String,
+    },
+    PtrInitialize {
+        owner_address: u64,
+        caller_pc: u64,
+        object_type: String,
+    },
+    PtrDestroy {
+        owner_address: u64,
+    },
+    // Answered once everything queued before it is done.
+    Flush(oneshot::Sender<()>),
+}
+
+// Queues operations for the worker started by NftPtrLib::spawn_worker. The worker does them
+// one at a time, in the order they were queued, so a move into an nft_ptr sees the
+// ptr_initialize queued before it. A failed operation is logged, and its error comes back
+// from the next flush or join.
+pub struct WorkerHandle {
+    sender: mpsc::Sender<WorkerOp>,
+    task: tokio::task::JoinHandle<()>,
+    // The first error since the last flush or join.
+    error: Arc<Mutex<Option<NftPtrError>>>,
+}
+
+impl WorkerHandle {
+    pub async fn move_token(
+        &self,
+        owner_address: u64,
+        previous_owner_address: u64,
+        value: u64,
+        caller_pc: u64,
+        object_type: &str,
+    ) -> Result<(), NftPtrError> {
+        self.enqueue(WorkerOp::MoveToken {
+            owner_address,
+            previous_owner_address,
+            value,
+            caller_pc,
+            object_type: object_type.to_string(),
+        })
+        .await
+    }
+
+    pub async fn ptr_initialize(
+        &self,
+        owner_address: u64,
+        caller_pc: u64,
+        ptr_object_type: &str,
+    ) -> Result<(), NftPtrError> {
+        self.enqueue(WorkerOp::PtrInitialize {
+            owner_address,
+            caller_pc,
+            object_type: ptr_object_type.to_string(),
+        })
+        .await
+    }
+
+    pub async fn ptr_destroy(&self, owner_address: u64) -> Result<(), NftPtrError> {
+        self.enqueue(WorkerOp::PtrDestroy { owner_address }).await
+    }
+
+    // Waits until everything queued so far is done, including checking the moves it sent
+    // in fire_and_forget mode.
+    pub async fn flush(&self) -> Result<(), NftPtrError> {
+        let (done, flushed) = oneshot::channel();
+        self.enqueue(WorkerOp::Flush(done)).await?;
+        flushed.await.map_err(|_| NftPtrError::WorkerStopped)?;
+        take_error(&self.error)
+    }
+
+    // Finishes everything queued, then stops the worker.
+    pub async fn join(self) -> Result<(), NftPtrError> {
+        drop(self.sender);
+        self.task.await.map_err(|_| NftPtrError::WorkerStopped)?;
+        take_error(&self.error)
+    }
+
+    // Waits for a free slot when the queue is full, so a program that allocates faster than
+    // the chain keeps up is slowed down rather than queueing without bound.
+    async fn enqueue(&self, op: WorkerOp) -> Result<(), NftPtrError> {
+        self.sender
+            .send(op)
+            .await
+            .map_err(|_| NftPtrError::WorkerStopped)
+    }
+}
+
+fn take_error(error: &Mutex<Option<NftPtrError>>) -> Result<(), NftPtrError> {
+    match error.lock().unwrap().take() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+fn record_error(
+    error: &Mutex<Option<NftPtrError>>,
+    operation: &str,
+    result: Result<(), NftPtrError>,
+) {
+    if let Err(err) = result {
+        warn!("Queued {} failed: {}", operation, err);
+        error.lock().unwrap().get_or_insert(err);
+    }
+}
+
+pub(crate) fn spawn<T>(lib: Arc<NftPtrLib<T>>, queue_size: usize) -> WorkerHandle
+where
+    T: web3::Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    let (sender, mut receiver) = mpsc::channel(queue_size.max(1));
+    let error = Arc::new(Mutex::new(None));
+    let task_error = error.clone();
+    let task = tokio::spawn(async move {
+        while let Some(op) = receiver.recv().await {
+            match op {
+                WorkerOp::MoveToken {
+                    owner_address,
+                    previous_owner_address,
+                    value,
+                    caller_pc,
+                    object_type,
+                } => {
+                    let result = lib
+                        .move_token(
+                            owner_address,
+                            previous_owner_address,
+                            value,
+                            caller_pc,
+                            &object_type,
+                        )
+                        .await;
+                    record_error(&task_error, "move_token", result.map(drop));
+                }
+                WorkerOp::PtrInitialize {
+                    owner_address,
+                    caller_pc,
+                    object_type,
+                } => {
+                    let result = lib
+                        .ptr_initialize(owner_address, caller_pc, &object_type)
+                        .await;
+                    let result = match result {
+                        Err(NftPtrError::Paused) => Ok(()),
+                        result => result.map(drop),
+                    };
+                    record_error(&task_error, "ptr_initialize", result);
+                }
+                WorkerOp::PtrDestroy { owner_address } => {
+                    let result = lib.ptr_destroy(owner_address).await;
+                    record_error(&task_error, "ptr_destroy", result.map(drop));
+                }
+                WorkerOp::Flush(done) => {
+                    record_error(&task_error, "flush", lib.flush().await);
+                    // The handle may have stopped waiting; nothing to do about that.
+                    let _ = done.send(());
+                }
+            }
+        }
+        // Every handle is gone, so this is join.
+        record_error(&task_error, "flush", lib.flush().await);
+    });
+    WorkerHandle {
+        sender,
+        task,
+        error,
+    }
+}
@@ -1,22 +1,65 @@
 #![feature(once_cell)]
 
-use nft_ptr_lib::{make_nft_ptr_lib, NftPtrLib, NftPtrLibTransport};
+use log::error;
+use nft_ptr_lib::{make_nft_ptr_lib, NftPtrError, NftPtrLib, NftPtrLibTransport};
+use std::borrow::Cow;
 use std::ffi::CStr;
 use std::lazy::SyncLazy;
-use std::sync::Mutex;
+
+// What every WdbNftPtr* function returns. A failure is logged rather than panicking, since
+// a panic can't unwind into the C++ that called us.
+const WDB_NFT_PTR_OK: i32 = 0;
+const WDB_NFT_PTR_ERROR: i32 = -1;
 
 static RUNTIME: SyncLazy<tokio::runtime::Runtime> =
     SyncLazy::new(|| tokio::runtime::Runtime::new().unwrap());
 
-// https://stackoverflow.com/questions/27791532/how-do-i-create-a-global-mutable-singleton
-static NFTPTRLIB: SyncLazy<Mutex<NftPtrLib<NftPtrLibTransport>>> = SyncLazy::new(|| {
+// NftPtrLib only needs &self once initialized, so threads calling in from C++ don't have to
+// queue up behind one another's transactions. None if it couldn't be set up; that's logged
+// once, and every call then fails.
+static NFTPTRLIB: SyncLazy<Option<NftPtrLib<NftPtrLibTransport>>> = SyncLazy::new(|| {
     // TODO(zhuowei): find a real place for this, haha
     env_logger::init();
-    let mut lib = RUNTIME.block_on(make_nft_ptr_lib());
-    RUNTIME.block_on(lib.initialize());
-    Mutex::new(lib)
+    let lib = RUNTIME.block_on(async {
+        let mut lib = make_nft_ptr_lib().await?;
+        lib.initialize().await?;
+        Ok::<_, NftPtrError>(lib)
+    });
+    match lib {
+        Ok(lib) => Some(lib),
+        Err(err) => {
+            error!("nft_ptr failed to start: {}", err);
+            None
+        }
+    }
 });
 
+fn status<T>(operation: &str, result: Result<T, NftPtrError>) -> i32 {
+    match result {
+        Ok(_) => WDB_NFT_PTR_OK,
+        Err(err) => {
+            error!("{} failed: {}", operation, err);
+            WDB_NFT_PTR_ERROR
+        }
+    }
+}
+
+// Runs `call` on the lib, if it started.
+fn with_lib<T>(
+    operation: &str,
+    call: impl FnOnce(&'static NftPtrLib<NftPtrLibTransport>) -> Result<T, NftPtrError>,
+) -> i32 {
+    match NFTPTRLIB.as_ref() {
+        Some(lib) => status(operation, call(lib)),
+        None => WDB_NFT_PTR_ERROR,
+    }
+}
+
+// Type names are only for display, so a stray non-UTF-8 byte isn't worth failing over.
+unsafe fn c_str<'a>(s: *const i8) -> Cow<'a, str> {
+    CStr::from_ptr(s).to_string_lossy()
+}
+
 /// # Safety
 /// ptr_object_type should contain a valid null-terminated string.
 #[no_mangle]
@@ -24,13 +67,15 @@ pub unsafe extern "C" fn WdbNftPtrInitialize(
     owner_address: u64,
     caller_pc: u64,
     ptr_object_type: *const i8,
-) {
-    let ptr_object_type_str = CStr::from_ptr(ptr_object_type).to_str().unwrap();
-    RUNTIME.block_on(NFTPTRLIB.lock().unwrap().ptr_initialize(
-        owner_address,
-        caller_pc,
-        ptr_object_type_str,
-    ));
+) -> i32 {
+    let ptr_object_type_str = c_str(ptr_object_type);
+    with_lib("ptr_initialize", |lib| {
+        match RUNTIME.block_on(lib.ptr_initialize(owner_address, caller_pc, &ptr_object_type_str)) {
+            // Pausing isn't an error for the instrumented program.
+            Err(NftPtrError::Paused) => Ok(()),
+            result => result.map(drop),
+        }
+    })
 }
 
 /// # Safety
@@ -42,20 +87,66 @@ pub unsafe extern "C" fn WdbNftPtrMoveToken(
     value: u64,
     caller_pc: u64,
     object_type: *const i8,
-) {
-    let object_type_str = CStr::from_ptr(object_type).to_str().unwrap();
-    RUNTIME.block_on(NFTPTRLIB.lock().unwrap().move_token(
-        owner_address,
-        previous_owner_address,
-        value,
-        caller_pc,
-        object_type_str,
-    ));
+) -> i32 {
+    let object_type_str = c_str(object_type);
+    with_lib("move_token", |lib| {
+        RUNTIME.block_on(lib.move_token(
+            owner_address,
+            previous_owner_address,
+            value,
+            caller_pc,
+            &object_type_str,
+        ))
+    })
+}
+
+/// # Safety
+/// object_type should contain a valid null-terminated string, and metadata_keys and
+/// metadata_values should each point to metadata_count valid null-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn WdbNftPtrMoveTokenWithMetadata(
+    owner_address: u64,
+    previous_owner_address: u64,
+    value: u64,
+    caller_pc: u64,
+    object_type: *const i8,
+    metadata_keys: *const *const i8,
+    metadata_values: *const *const i8,
+    metadata_count: usize,
+) -> i32 {
+    let object_type_str = c_str(object_type);
+    let metadata_strs: Vec<(Cow<str>, Cow<str>)> = (0..metadata_count)
+        .map(|i| (c_str(*metadata_keys.add(i)), c_str(*metadata_values.add(i))))
+        .collect();
+    let metadata: Vec<(&str, &str)> = metadata_strs
+        .iter()
+        .map(|(key, value)| (&**key, &**value))
+        .collect();
+    with_lib("move_token_with_metadata", |lib| {
+        RUNTIME.block_on(lib.move_token_with_metadata(
+            owner_address,
+            previous_owner_address,
+            value,
+            caller_pc,
+            &object_type_str,
+            &metadata,
+        ))
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn WdbNftPtrDestroy(owner_address: u64) -> i32 {
+    with_lib("ptr_destroy", |lib| {
+        RUNTIME.block_on(lib.ptr_destroy(owner_address))
+    })
 }
 
+// Call before exiting, so moves that are still being mined aren't lost.
 #[no_mangle]
-pub extern "C" fn WdbNftPtrDestroy(owner_address: u64) {
-    RUNTIME.block_on(NFTPTRLIB.lock().unwrap().ptr_destroy(owner_address));
+pub extern "C" fn WdbNftPtrShutdown(num_confirmations: u64) -> i32 {
+    with_lib("shutdown", |lib| {
+        RUNTIME.block_on(lib.shutdown(num_confirmations as usize))
+    })
 }
 
 #[cfg(test)]
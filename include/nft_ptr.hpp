@@ -1,13 +1,25 @@
 #pragma once
+#include <cstddef>
 #include <cstdint>
 
 extern "C" {
-void WdbNftPtrInitialize(uint64_t owner_address, uint64_t caller_pc,
-                         const char* ptr_object_type);
-void WdbNftPtrMoveToken(uint64_t owner_address, uint64_t previous_owner_address,
-                        uint64_t value, uint64_t caller_pc,
-                        const char* object_type);
-void WdbNftPtrDestroy(uint64_t owner_address);
+// Each of these returns 0 on success, or -1 if it failed; the error is logged.
+int32_t WdbNftPtrInitialize(uint64_t owner_address, uint64_t caller_pc,
+                            const char* ptr_object_type);
+int32_t WdbNftPtrMoveToken(uint64_t owner_address,
+                           uint64_t previous_owner_address, uint64_t value,
+                           uint64_t caller_pc, const char* object_type);
+// Like WdbNftPtrMoveToken, but also puts metadata_count key/value pairs (e.g. "thread",
+// "3") in the token URI for the metadata server to show.
+int32_t WdbNftPtrMoveTokenWithMetadata(uint64_t owner_address,
+                                       uint64_t previous_owner_address,
+                                       uint64_t value, uint64_t caller_pc,
+                                       const char* object_type,
+                                       const char* const* metadata_keys,
+                                       const char* const* metadata_values,
+                                       size_t metadata_count);
+int32_t WdbNftPtrDestroy(uint64_t owner_address);
+int32_t WdbNftPtrShutdown(uint64_t num_confirmations);
 }  // extern "C"
 
 namespace wdb {